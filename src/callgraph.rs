@@ -0,0 +1,219 @@
+use std::{collections::BTreeMap, io::Write};
+
+use crate::{intrinsics, replay::ReplayEvent};
+
+/// Builds the dynamic call graph of a replay, following the procedures entered and returned from,
+/// and those skipped over.
+#[derive(Debug, Default)]
+pub struct CallGraph {
+    nodes: BTreeMap<String, CallNode>,
+    edges: BTreeMap<(String, String), CallEdge>,
+
+    // Each procedure called and the cycles spent in it so far.
+    frames: Vec<(String, u64)>,
+}
+
+/// How a procedure was replayed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProcKind {
+    /// Replayed against its source.
+    Known,
+
+    /// Skipped over, not being in the source or not traced.
+    Skipped,
+
+    /// Skipped over but simulated, being a known intrinsic.
+    Intrinsic,
+}
+
+/// A procedure in the call graph, with the cycles spent in all its calls.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CallNode {
+    pub kind: ProcKind,
+    pub calls: u64,
+    pub cycles: u64,
+}
+
+/// The calls from one procedure to another, with the cycles spent in them.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CallEdge {
+    pub calls: u64,
+    pub cycles: u64,
+}
+
+impl CallGraph {
+    pub fn record(&mut self, event: &ReplayEvent) {
+        match event {
+            ReplayEvent::Entry { func } => {
+                self.return_all();
+                self.node(func, ProcKind::Known).calls += 1;
+                self.frames.push((func.clone(), 0));
+            }
+
+            ReplayEvent::Enter { callee, .. } => {
+                self.node(callee, ProcKind::Known).calls += 1;
+                self.frames.push((callee.clone(), 0));
+            }
+
+            ReplayEvent::Return { .. } | ReplayEvent::EntryReturned => self.return_from(),
+
+            ReplayEvent::OpExecuted { cycles, .. } => {
+                if let Some((_, frame_cycles)) = self.frames.last_mut() {
+                    *frame_cycles += cycles;
+                }
+            }
+
+            ReplayEvent::SkippedCycles { callee, cycles, .. } => {
+                let kind = if intrinsics::is_simulated(callee) {
+                    ProcKind::Intrinsic
+                } else {
+                    ProcKind::Skipped
+                };
+                let node = self.node(callee, kind);
+                node.calls += 1;
+                node.cycles += cycles;
+
+                if let Some((caller, caller_cycles)) = self.frames.last_mut() {
+                    *caller_cycles += cycles;
+                    let edge = self
+                        .edges
+                        .entry((caller.clone(), callee.clone()))
+                        .or_default();
+                    edge.calls += 1;
+                    edge.cycles += cycles;
+                }
+            }
+
+            ReplayEvent::EndOfTrace => self.return_all(),
+
+            _ => {}
+        }
+    }
+
+    fn node(&mut self, func: &str, kind: ProcKind) -> &mut CallNode {
+        self.nodes.entry(func.to_string()).or_insert(CallNode {
+            kind,
+            calls: 0,
+            cycles: 0,
+        })
+    }
+
+    // Attribute the cycles of the innermost call to it and its caller.
+    fn return_from(&mut self) {
+        let Some((callee, cycles)) = self.frames.pop() else {
+            return;
+        };
+        if let Some(node) = self.nodes.get_mut(&callee) {
+            node.cycles += cycles;
+        }
+        if let Some((caller, caller_cycles)) = self.frames.last_mut() {
+            *caller_cycles += cycles;
+            let edge = self.edges.entry((caller.clone(), callee)).or_default();
+            edge.calls += 1;
+            edge.cycles += cycles;
+        }
+    }
+
+    // Count the cycles of calls still open when the trace ends or the replay restarts, so each
+    // is credited to its callers as though it returned there.
+    fn return_all(&mut self) {
+        while !self.frames.is_empty() {
+            self.return_from();
+        }
+    }
+
+    /// Each procedure called, by name.
+    pub fn nodes(&self) -> &BTreeMap<String, CallNode> {
+        &self.nodes
+    }
+
+    /// The calls between each caller and callee.
+    pub fn edges(&self) -> &BTreeMap<(String, String), CallEdge> {
+        &self.edges
+    }
+
+    /// Write the graph in Graphviz DOT.  Skipped procedures are dashed and intrinsics also grey.
+    pub fn write_dot(&self, mut out: impl Write) -> anyhow::Result<()> {
+        let escape = |name: &str| name.replace('\\', "\\\\").replace('"', "\\\"");
+
+        writeln!(out, "digraph callgraph {{")?;
+        writeln!(out, "    node [shape=box];")?;
+        for (func, node) in &self.nodes {
+            let style = match node.kind {
+                ProcKind::Known => "",
+                ProcKind::Skipped => ", style=dashed",
+                ProcKind::Intrinsic => ", style=\"dashed,filled\", fillcolor=lightgrey",
+            };
+            writeln!(
+                out,
+                "    \"{}\" [label=\"{}\\n{}, {} cycles\"{style}];",
+                escape(func),
+                escape(func),
+                plural(node.calls, "call"),
+                node.cycles
+            )?;
+        }
+        for ((caller, callee), edge) in &self.edges {
+            writeln!(
+                out,
+                "    \"{}\" -> \"{}\" [label=\"{}, {} cycles\"];",
+                escape(caller),
+                escape(callee),
+                plural(edge.calls, "call"),
+                edge.cycles
+            )?;
+        }
+        writeln!(out, "}}")?;
+
+        Ok(())
+    }
+
+    /// Write the graph as a JSON object of `nodes` and `edges`.
+    pub fn write_json(&self, mut out: impl Write) -> anyhow::Result<()> {
+        use serde_json::json;
+
+        let nodes = self
+            .nodes
+            .iter()
+            .map(|(func, node)| {
+                json!({
+                    "name": func,
+                    "kind": match node.kind {
+                        ProcKind::Known => "known",
+                        ProcKind::Skipped => "skipped",
+                        ProcKind::Intrinsic => "intrinsic",
+                    },
+                    "calls": node.calls,
+                    "cycles": node.cycles,
+                })
+            })
+            .collect::<Vec<_>>();
+        let edges = self
+            .edges
+            .iter()
+            .map(|((caller, callee), edge)| {
+                json!({
+                    "caller": caller,
+                    "callee": callee,
+                    "calls": edge.calls,
+                    "cycles": edge.cycles,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        serde_json::to_writer_pretty(&mut out, &json!({ "nodes": nodes, "edges": edges }))?;
+        writeln!(out)?;
+
+        Ok(())
+    }
+}
+
+fn plural(count: u64, noun: &str) -> String {
+    if count == 1 {
+        format!("{count} {noun}")
+    } else {
+        format!("{count} {noun}s")
+    }
+}
+
+// vim:fdl=3
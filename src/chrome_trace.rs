@@ -0,0 +1,144 @@
+use std::io::Write;
+
+use serde_json::{Value, json};
+
+use crate::replay::ReplayEvent;
+
+/// Builds a timeline of a replay in the Chrome trace event format, as loaded by `chrome://tracing`
+/// and Perfetto.  Timestamps are VM cycles, as indices into the trace.
+#[derive(Debug, Default)]
+pub struct ChromeTrace {
+    events: Vec<Value>,
+
+    // The trace index of the last cycle replayed.
+    cycle: u64,
+
+    // The entry function's begin event, which is timed by the first cycle replayed in it.
+    pending_entry: Option<usize>,
+
+    // The procedures begun and not yet ended, innermost last.
+    open: Vec<String>,
+}
+
+impl ChromeTrace {
+    pub fn record(&mut self, event: &ReplayEvent) {
+        match event {
+            ReplayEvent::Entry { func } => {
+                self.end_all();
+                self.begin(func, "proc");
+                self.pending_entry = Some(self.events.len() - 1);
+            }
+            ReplayEvent::Enter { callee, .. } => self.begin(callee, "proc"),
+            ReplayEvent::Return { .. } | ReplayEvent::EntryReturned => self.end(),
+
+            ReplayEvent::OpExecuted {
+                op, cycle, cycles, ..
+            } => {
+                self.advance(*cycle as u64, *cycles);
+                if op.opcode().is_some_and(|opcode| opcode.contains("assert")) {
+                    self.instant(&op.to_string(), json!({ "cycle": cycle }));
+                }
+            }
+
+            // Not every skipped call is traced, so they're only shown once they've returned.
+            ReplayEvent::SkippedCycles {
+                callee,
+                cycles,
+                cycle,
+                ..
+            } => {
+                let cycle = *cycle as u64;
+                self.cycle = cycle.saturating_sub(*cycles);
+                self.begin(callee, "skipped");
+                self.advance(cycle, *cycles);
+                self.end();
+            }
+
+            ReplayEvent::MemoryMismatch {
+                addr,
+                loaded,
+                stored,
+            } => self.instant(
+                "memory mismatch",
+                json!({ "addr": addr, "loaded": loaded, "stored": stored }),
+            ),
+            // The executor reports the failure by its VM clock, which isn't a trace index.
+            ReplayEvent::Failure { op, report, .. } => {
+                self.instant(
+                    &format!("FAILED: {op}"),
+                    json!({ "cycle": report.cycle, "message": report.message }),
+                );
+            }
+
+            ReplayEvent::EndOfTrace => self.end_all(),
+
+            _ => {}
+        }
+    }
+
+    // Move on to the final cycle of an op or call which took `cycles` cycles.
+    fn advance(&mut self, cycle: u64, cycles: u64) {
+        if let Some(entry_idx) = self.pending_entry.take() {
+            self.events[entry_idx]["ts"] = json!(cycle.saturating_sub(cycles));
+        }
+        self.cycle = cycle;
+    }
+
+    fn begin(&mut self, func: &str, category: &str) {
+        self.events.push(json!({
+            "name": func,
+            "cat": category,
+            "ph": "B",
+            "ts": self.cycle,
+            "pid": 1,
+            "tid": 1,
+        }));
+        self.open.push(func.to_string());
+    }
+
+    fn end(&mut self) {
+        if let Some(func) = self.open.pop() {
+            self.events.push(json!({
+                "name": func,
+                "ph": "E",
+                "ts": self.cycle,
+                "pid": 1,
+                "tid": 1,
+            }));
+        }
+    }
+
+    // Close the slices of calls still open when the trace ends or the replay restarts, so every
+    // `B` event has a matching `E`.
+    fn end_all(&mut self) {
+        while !self.open.is_empty() {
+            self.end();
+        }
+    }
+
+    fn instant(&mut self, name: &str, args: Value) {
+        self.events.push(json!({
+            "name": name,
+            "ph": "i",
+            "s": "t",
+            "ts": self.cycle,
+            "pid": 1,
+            "tid": 1,
+            "args": args,
+        }));
+    }
+
+    /// Write the trace as a JSON object, one event per line.
+    pub fn write(&self, mut out: impl Write) -> anyhow::Result<()> {
+        writeln!(out, "{{\"traceEvents\": [")?;
+        for (idx, event) in self.events.iter().enumerate() {
+            let sep = if idx + 1 < self.events.len() { "," } else { "" };
+            writeln!(out, "  {event}{sep}")?;
+        }
+        writeln!(out, "]}}")?;
+
+        Ok(())
+    }
+}
+
+// vim:fdl=3
@@ -0,0 +1,166 @@
+use std::io::Write;
+
+use crate::{
+    masm::{BlockKey, Op, SourceBlocks, SourceLine},
+    src_mgr::{self, SourcePos},
+};
+
+/// Which source ops ran during a replay, and which ways the branches went.
+#[derive(Debug, Default)]
+pub struct Coverage {
+    hits: fxhash::FxHashMap<SourcePos, u64>,
+
+    // For conditionals the number of times the then and else arms were taken.  For loops the
+    // number of times the body was entered and skipped.
+    arms: fxhash::FxHashMap<SourcePos, [u64; 2]>,
+}
+
+/// The coverage totals for a single procedure.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ProcCoverage {
+    pub ops: usize,
+    pub ops_hit: usize,
+    pub arms: usize,
+    pub arms_taken: usize,
+}
+
+impl Coverage {
+    pub(crate) fn hit(&mut self, pos: SourcePos) {
+        *self.hits.entry(pos).or_default() += 1;
+    }
+
+    pub(crate) fn branch(&mut self, pos: SourcePos, first_arm: bool) {
+        self.hit(pos);
+        self.arms.entry(pos).or_default()[usize::from(!first_arm)] += 1;
+    }
+
+    /// How many times the op at `pc` in the block ran.
+    pub fn hits(&self, block_key: BlockKey, pc: usize) -> u64 {
+        let pos = SourcePos {
+            block: block_key,
+            pc,
+        };
+        self.hits.get(&pos).copied().unwrap_or_default()
+    }
+
+    /// How many times each arm of the conditional or loop at `pc` in the block was taken.
+    pub fn arms(&self, block_key: BlockKey, pc: usize) -> [u64; 2] {
+        let pos = SourcePos {
+            block: block_key,
+            pc,
+        };
+        self.arms.get(&pos).copied().unwrap_or_default()
+    }
+
+    /// The totals for the procedure, including its nested blocks.
+    pub fn proc_coverage(&self, blocks: &SourceBlocks, block_key: BlockKey) -> ProcCoverage {
+        let mut totals = ProcCoverage::default();
+        for line in blocks.lines(block_key) {
+            let SourceLine::Op { block, pc, op, .. } = line else {
+                continue;
+            };
+            totals.ops += 1;
+            if self.hits(block, pc) != 0 {
+                totals.ops_hit += 1;
+            }
+            if matches!(op, Op::Conditional { .. } | Op::Loop(_)) {
+                let arms = self.arms(block, pc);
+                totals.arms += 2;
+                totals.arms_taken += arms.iter().filter(|count| **count != 0).count();
+            }
+        }
+        totals
+    }
+
+    /// Write the source annotated with the hit count of every op, marking those which never ran
+    /// and the branches never taken, with a summary for each procedure.
+    pub fn write_report(&self, blocks: &SourceBlocks, mut out: impl Write) -> anyhow::Result<()> {
+        for (block_key, block) in blocks.iter() {
+            let Some(name) = block.name() else {
+                continue;
+            };
+
+            let ProcCoverage {
+                ops,
+                ops_hit,
+                arms,
+                arms_taken,
+            } = self.proc_coverage(blocks, block_key);
+            writeln!(out, "proc {name}")?;
+            self.write_lines(blocks, block_key, &mut out)?;
+            writeln!(
+                out,
+                "end  # ops {ops_hit}/{ops} ({}%), branches {arms_taken}/{arms}",
+                percent(ops_hit, ops)
+            )?;
+            writeln!(out)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_lines(
+        &self,
+        blocks: &SourceBlocks,
+        block_key: BlockKey,
+        out: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        // The procedure's body is indented within it.
+        let indent = |depth: usize| " ".repeat((depth + 1) * src_mgr::INDENT_AMOUNT);
+
+        for line in blocks.lines(block_key) {
+            let (block, pc, op, depth) = match line {
+                SourceLine::Op {
+                    block,
+                    pc,
+                    op,
+                    depth,
+                } => (block, pc, op, depth),
+                SourceLine::Else { depth } => {
+                    writeln!(out, "{:>10}  {}else", "", indent(depth))?;
+                    continue;
+                }
+                SourceLine::End { depth } => {
+                    writeln!(out, "{:>10}  {}end", "", indent(depth))?;
+                    continue;
+                }
+            };
+
+            let hits = self.hits(block, pc);
+            let count = if hits == 0 {
+                "#####".to_string()
+            } else {
+                hits.to_string()
+            };
+            write!(out, "{count:>10}  {}{op}", indent(depth))?;
+
+            let [first_arm, second_arm] = self.arms(block, pc);
+            match op {
+                Op::Conditional { .. } if hits != 0 && first_arm == 0 => {
+                    write!(out, "  <<< then never taken")?
+                }
+                Op::Conditional {
+                    else_block: Some(_),
+                    ..
+                } if hits != 0 && second_arm == 0 => write!(out, "  <<< else never taken")?,
+                Op::Conditional { .. } if hits != 0 && second_arm == 0 => {
+                    write!(out, "  <<< never skipped")?
+                }
+                Op::Loop(_) if hits != 0 && first_arm == 0 => {
+                    write!(out, "  <<< body never entered")?
+                }
+                Op::Loop(_) if hits != 0 && second_arm == 0 => write!(out, "  <<< never skipped")?,
+                _ => {}
+            }
+            writeln!(out)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn percent(part: usize, whole: usize) -> usize {
+    (part * 100).checked_div(whole).unwrap_or(100)
+}
+
+// vim:fdl=3
@@ -0,0 +1,510 @@
+use std::io::{BufRead, Write};
+
+use anyhow::Context;
+use serde_json::{Value, json};
+
+use crate::{
+    debugger::{self, Debugger, Step},
+    masm::BlockKey,
+    render,
+    replay::{Replay, ReplayOptions},
+    src_mgr::SourcePos,
+};
+
+// A replay has just the one thread.
+const THREAD_ID: u64 = 1;
+
+const STACK_VARIABLES_REF: u64 = 1;
+const MEMORY_VARIABLES_REF: u64 = 2;
+
+/// A Debug Adapter Protocol server, so a replay may be stepped through from an editor.
+/// Breakpoints are set on procedure names and stop on entry to a matching procedure.
+pub struct DapServer<R: BufRead, W: Write> {
+    input: R,
+    output: W,
+    seq: u64,
+
+    debugger: Option<Debugger>,
+    stop_on_entry: bool,
+
+    // The procedure names to stop at, matched against the end of the source function names.
+    breakpoints: Vec<String>,
+
+    // The procedures whose listings have been given out as sources; the source reference is the
+    // index plus one.
+    sources: Vec<BlockKey>,
+
+    // The number of steps whose notes have been sent as output.
+    steps_reported: usize,
+}
+
+enum Motion {
+    Continue,
+    Next,
+    StepIn,
+    StepOut,
+    StepBack,
+    ReverseContinue,
+}
+
+impl<R: BufRead, W: Write> DapServer<R, W> {
+    pub fn new(input: R, output: W) -> Self {
+        Self {
+            input,
+            output,
+            seq: 0,
+            debugger: None,
+            stop_on_entry: true,
+            breakpoints: Vec::new(),
+            sources: Vec::new(),
+            steps_reported: 0,
+        }
+    }
+
+    /// Serve requests until the client disconnects.
+    pub fn run(&mut self) -> anyhow::Result<()> {
+        while let Some(request) = self.read_message()? {
+            if !self.handle_request(&request)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> W {
+        self.output
+    }
+
+    fn read_message(&mut self) -> anyhow::Result<Option<Value>> {
+        let mut content_len = None;
+        loop {
+            let mut line = String::new();
+            if self.input.read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some(len) = line.strip_prefix("Content-Length:") {
+                content_len = Some(len.trim().parse::<usize>()?);
+            }
+        }
+
+        let content_len = content_len.context("DAP message is missing its Content-Length")?;
+        let mut content = vec![0; content_len];
+        self.input.read_exact(&mut content)?;
+        Ok(Some(serde_json::from_slice(&content)?))
+    }
+
+    fn send(&mut self, message: Value) -> anyhow::Result<()> {
+        let content = message.to_string();
+        write!(
+            self.output,
+            "Content-Length: {}\r\n\r\n{content}",
+            content.len()
+        )?;
+        self.output.flush()?;
+        Ok(())
+    }
+
+    fn next_seq(&mut self) -> u64 {
+        self.seq += 1;
+        self.seq
+    }
+
+    fn respond(&mut self, request: &Value, body: Value) -> anyhow::Result<()> {
+        let seq = self.next_seq();
+        self.send(json!({
+            "seq": seq,
+            "type": "response",
+            "request_seq": request["seq"],
+            "success": true,
+            "command": request["command"],
+            "body": body,
+        }))
+    }
+
+    fn respond_error(&mut self, request: &Value, message: String) -> anyhow::Result<()> {
+        let seq = self.next_seq();
+        self.send(json!({
+            "seq": seq,
+            "type": "response",
+            "request_seq": request["seq"],
+            "success": false,
+            "command": request["command"],
+            "message": message,
+        }))
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> anyhow::Result<()> {
+        let seq = self.next_seq();
+        self.send(json!({
+            "seq": seq,
+            "type": "event",
+            "event": event,
+            "body": body,
+        }))
+    }
+
+    // Returns false once the client has disconnected.
+    fn handle_request(&mut self, request: &Value) -> anyhow::Result<bool> {
+        let args = &request["arguments"];
+        let command = request["command"].as_str().unwrap_or_default();
+
+        match command {
+            "initialize" => self.respond(
+                request,
+                json!({
+                    "supportsConfigurationDoneRequest": true,
+                    "supportsFunctionBreakpoints": true,
+                    "supportsStepBack": true,
+                }),
+            )?,
+
+            "launch" => match self.launch(args) {
+                Ok(()) => {
+                    self.respond(request, Value::Null)?;
+                    self.event("initialized", Value::Null)?;
+                }
+                Err(err) => self.respond_error(request, format!("{err:#}"))?,
+            },
+
+            "setFunctionBreakpoints" => {
+                self.breakpoints = args["breakpoints"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|bp| bp["name"].as_str().map(str::to_string))
+                    .collect();
+                let verified = self
+                    .breakpoints
+                    .iter()
+                    .map(|_| json!({ "verified": true }))
+                    .collect::<Vec<_>>();
+                self.respond(request, json!({ "breakpoints": verified }))?;
+            }
+
+            "setBreakpoints" => {
+                let unverified = args["breakpoints"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .map(|_| {
+                        json!({
+                            "verified": false,
+                            "message": "Breakpoints are set on procedure names.",
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                self.respond(request, json!({ "breakpoints": unverified }))?;
+            }
+
+            "setExceptionBreakpoints" => self.respond(request, json!({ "breakpoints": [] }))?,
+
+            "configurationDone" => {
+                self.respond(request, Value::Null)?;
+                if self.stop_on_entry {
+                    self.report_notes()?;
+                    self.stopped("entry", None)?;
+                } else {
+                    self.resume(Motion::Continue)?;
+                }
+            }
+
+            "threads" => self.respond(
+                request,
+                json!({ "threads": [{ "id": THREAD_ID, "name": "main" }] }),
+            )?,
+
+            "stackTrace" => {
+                let frames = self.stack_frames();
+                let total = frames.len();
+                self.respond(
+                    request,
+                    json!({ "stackFrames": frames, "totalFrames": total }),
+                )?;
+            }
+
+            "scopes" => self.respond(
+                request,
+                json!({ "scopes": [
+                    {
+                        "name": "Operand Stack",
+                        "variablesReference": STACK_VARIABLES_REF,
+                        "expensive": false,
+                    },
+                    {
+                        "name": "Memory",
+                        "variablesReference": MEMORY_VARIABLES_REF,
+                        "expensive": false,
+                    },
+                ]}),
+            )?,
+
+            "variables" => {
+                let variables = self.variables(args["variablesReference"].as_u64());
+                self.respond(request, json!({ "variables": variables }))?;
+            }
+
+            "source" => {
+                let listing = args["sourceReference"]
+                    .as_u64()
+                    .and_then(|source_ref| self.source_listing(source_ref));
+                match listing {
+                    Some(content) => self.respond(
+                        request,
+                        json!({ "content": content, "mimeType": "text/x-masm" }),
+                    )?,
+                    None => self.respond_error(request, "Unknown source.".to_string())?,
+                }
+            }
+
+            "continue" | "next" | "stepIn" | "stepOut" | "stepBack" | "reverseContinue" => {
+                if self.debugger.is_none() {
+                    self.respond_error(request, "No trace has been launched.".to_string())?;
+                    return Ok(true);
+                }
+
+                let motion = match command {
+                    "continue" => Motion::Continue,
+                    "next" => Motion::Next,
+                    "stepIn" => Motion::StepIn,
+                    "stepOut" => Motion::StepOut,
+                    "stepBack" => Motion::StepBack,
+                    _ => Motion::ReverseContinue,
+                };
+                let body = matches!(motion, Motion::Continue)
+                    .then(|| json!({ "allThreadsContinued": true }))
+                    .unwrap_or_default();
+                self.respond(request, body)?;
+                self.resume(motion)?;
+            }
+
+            // The replay only ever runs in response to a request, so is always paused.
+            "pause" => self.respond(request, Value::Null)?,
+
+            "disconnect" | "terminate" => {
+                self.respond(request, Value::Null)?;
+                return Ok(false);
+            }
+
+            _ => self.respond_error(request, format!("Unsupported request '{command}'."))?,
+        }
+
+        Ok(true)
+    }
+
+    fn launch(&mut self, args: &Value) -> anyhow::Result<()> {
+        let program = args["program"]
+            .as_str()
+            .context("Launch needs the 'program' log file to replay")?;
+        let (src_items, trace) = crate::parse_log(crate::open_log(program)?)?;
+
+        let options = ReplayOptions {
+            entry_func: args["entryFunc"].as_str().map(str::to_string),
+            repeat: args["repeat"].as_bool().unwrap_or_default(),
+            raw_symbols: args["rawSymbols"].as_bool().unwrap_or_default(),
+            strict: args["strict"].as_bool().unwrap_or_default(),
+            check_semantics: args["checkSemantics"].as_bool().unwrap_or_default(),
+            ..Default::default()
+        };
+
+        self.debugger = Some(Debugger::new(Replay::new(src_items, trace, options)?));
+        self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(true);
+        self.steps_reported = 0;
+        Ok(())
+    }
+
+    fn resume(&mut self, motion: Motion) -> anyhow::Result<()> {
+        let breakpoints = &self.breakpoints;
+        let Some(debugger) = &mut self.debugger else {
+            return Ok(());
+        };
+
+        let at_breakpoint =
+            |step: &Step| step.entered && breakpoints.iter().any(|name| step.func.ends_with(name));
+
+        let (moved, reason) = match motion {
+            Motion::Continue => (debugger.step_until(at_breakpoint), "breakpoint"),
+            Motion::Next => (debugger.step_over(), "step"),
+            Motion::StepIn => (debugger.step(), "step"),
+            Motion::StepOut => (debugger.step_out(), "step"),
+            Motion::StepBack => (debugger.step_back(), "step"),
+            Motion::ReverseContinue => {
+                let hit = debugger.step_back_until(at_breakpoint);
+                (true, if hit { "breakpoint" } else { "entry" })
+            }
+        };
+
+        let failure = debugger
+            .current()
+            .and_then(|step| step.failure.clone())
+            .or_else(|| debugger.error().map(str::to_string));
+        let finished = debugger.at_end();
+
+        self.report_notes()?;
+        match motion {
+            Motion::StepBack | Motion::ReverseContinue => self.stopped(reason, None),
+            _ if !finished => self.stopped(reason, None),
+
+            // Stop at the end of a failed trace so it may be inspected, or stepped back from.
+            _ if failure.is_some() => self.stopped("exception", failure),
+
+            // Let a step arrive at the last op, but anything further ends the session.
+            _ if moved => self.stopped(reason, None),
+            _ => {
+                self.event("exited", json!({ "exitCode": 0 }))?;
+                self.event("terminated", Value::Null)
+            }
+        }
+    }
+
+    fn stopped(&mut self, reason: &str, text: Option<String>) -> anyhow::Result<()> {
+        let mut body = json!({
+            "reason": reason,
+            "threadId": THREAD_ID,
+            "allThreadsStopped": true,
+        });
+        if let Some(text) = text {
+            body["text"] = text.into();
+        }
+        self.event("stopped", body)
+    }
+
+    // Send the notes of the steps passed through since they were last reported as output.
+    fn report_notes(&mut self) -> anyhow::Result<()> {
+        let Some(debugger) = &self.debugger else {
+            return Ok(());
+        };
+
+        let notes = debugger
+            .steps()
+            .iter()
+            .take(debugger.cursor() + 1)
+            .skip(self.steps_reported)
+            .flat_map(|step| step.notes.clone())
+            .collect::<Vec<_>>();
+        self.steps_reported = self.steps_reported.max(debugger.cursor() + 1);
+
+        for note in notes {
+            self.event(
+                "output",
+                json!({ "category": "console", "output": note + "\n" }),
+            )?;
+        }
+        Ok(())
+    }
+
+    // The frames of the current step, innermost first.
+    fn stack_frames(&mut self) -> Vec<Value> {
+        let Some(step) = self.debugger.as_ref().and_then(Debugger::current) else {
+            return Vec::new();
+        };
+
+        // Each frame is at the call into the next, and the innermost is at the current op.
+        let call_stack = step.call_stack.clone();
+        let positions = call_stack
+            .iter()
+            .skip(1)
+            .map(|frame| frame.call_site)
+            .chain(std::iter::once(Some(step.pos)))
+            .collect::<Vec<_>>();
+
+        call_stack
+            .iter()
+            .zip(positions)
+            .enumerate()
+            .rev()
+            .map(|(frame_id, (frame, pos))| {
+                let mut stack_frame = json!({
+                    "id": frame_id,
+                    "name": frame.func,
+                    "line": 0,
+                    "column": 0,
+                });
+                if let Some((source_ref, line)) = pos.and_then(|pos| self.locate(pos)) {
+                    stack_frame["source"] = json!({
+                        "name": frame.func,
+                        "sourceReference": source_ref,
+                    });
+                    stack_frame["line"] = line.into();
+                    stack_frame["column"] = 1.into();
+                }
+                stack_frame
+            })
+            .collect()
+    }
+
+    // The source reference for the procedure containing the position, and the line it's on.
+    fn locate(&mut self, pos: SourcePos) -> Option<(usize, usize)> {
+        let debugger = self.debugger.as_ref()?;
+        let proc_key = debugger.proc_of(pos.block)?;
+        let line_idx = debugger::proc_listing(debugger.blocks(), proc_key)
+            .iter()
+            .position(|(line_pos, _)| *line_pos == Some(pos))?;
+
+        let source_idx = match self.sources.iter().position(|key| *key == proc_key) {
+            Some(source_idx) => source_idx,
+            None => {
+                self.sources.push(proc_key);
+                self.sources.len() - 1
+            }
+        };
+        Some((source_idx + 1, line_idx + 1))
+    }
+
+    fn source_listing(&self, source_ref: u64) -> Option<String> {
+        let debugger = self.debugger.as_ref()?;
+        let proc_key = *self
+            .sources
+            .get(usize::try_from(source_ref).ok()?.checked_sub(1)?)?;
+        let lines = debugger::proc_listing(debugger.blocks(), proc_key)
+            .into_iter()
+            .map(|(_, text)| text)
+            .collect::<Vec<_>>();
+        Some(lines.join("\n") + "\n")
+    }
+
+    fn variables(&self, variables_ref: Option<u64>) -> Vec<Value> {
+        let Some(debugger) = &self.debugger else {
+            return Vec::new();
+        };
+
+        match variables_ref {
+            Some(STACK_VARIABLES_REF) => debugger
+                .current()
+                .map(|step| {
+                    step.stack
+                        .iter()
+                        .enumerate()
+                        .map(|(idx, val)| {
+                            json!({
+                                "name": idx.to_string(),
+                                "value": render::format_felt(*val),
+                                "variablesReference": 0,
+                            })
+                        })
+                        .collect()
+                })
+                .unwrap_or_default(),
+
+            Some(MEMORY_VARIABLES_REF) => debugger
+                .memory()
+                .words()
+                .map(|(base_addr, words)| {
+                    json!({
+                        "name": format!("{base_addr:0>8x}"),
+                        "value": render::format_word(&words),
+                        "variablesReference": 0,
+                    })
+                })
+                .collect(),
+
+            _ => Vec::new(),
+        }
+    }
+}
+
+// vim:fdl=3
@@ -0,0 +1,437 @@
+use std::rc::Rc;
+
+use crate::{
+    masm::{BlockKey, Op, SourceBlocks, SourceLine},
+    memory::{FeltChange, Memory},
+    render::{Renderer, TextRenderer},
+    replay::{Replay, ReplayEvent},
+    src_mgr::{self, SourcePos},
+};
+
+/// A single step of a replay, i.e., a source op which ran or was skipped over.
+#[derive(Clone, Debug)]
+pub struct Step {
+    pub func: String,
+    pub op: Op,
+    pub pos: SourcePos,
+
+    /// The index into the trace of the op's final cycle.
+    pub cycle: usize,
+    pub stack: Vec<u64>,
+
+    /// The procedures on the call stack, outermost first.
+    pub call_stack: Rc<Vec<Frame>>,
+
+    /// Whether this is the first step of a newly entered procedure.
+    pub entered: bool,
+
+    /// Anything else of note which happened with this step, e.g., a resync.
+    pub notes: Vec<String>,
+
+    /// The executor's failure message, if the trace ended with this step failing.
+    pub failure: Option<String>,
+}
+
+/// A procedure on the call stack.
+#[derive(Clone, Debug)]
+pub struct Frame {
+    pub func: String,
+
+    /// The position of the call in the caller, unless this is the entry function.
+    pub call_site: Option<SourcePos>,
+}
+
+/// Steps through a replay, forwards and backwards.  The replay is only run as far as needed, but
+/// every step reached is kept so it may be returned to.
+pub struct Debugger {
+    replay: Replay,
+    history: Vec<Step>,
+    cursor: usize,
+
+    // The memory words reported after each step, as (step index, base address, words).
+    mem_log: Vec<(usize, u64, [Option<u64>; 4])>,
+
+    // The memory as of the cursor, and the changes made by each step, as (step index, change), of
+    // which the first `mem_changes_applied` have been made.  Changes are pending until the step
+    // they belong to is known.
+    memory: Memory,
+    mem_changes: Vec<(usize, FeltChange)>,
+    mem_changes_applied: usize,
+    pending_mem_changes: Vec<FeltChange>,
+
+    // The procedure which each block is within.
+    block_procs: fxhash::FxHashMap<BlockKey, BlockKey>,
+
+    call_stack: Rc<Vec<Frame>>,
+    pending_entered: bool,
+    pending_notes: Vec<String>,
+    finished: bool,
+    error: Option<String>,
+}
+
+impl Debugger {
+    pub fn new(mut replay: Replay) -> Self {
+        replay.journal_memory();
+        let block_procs = block_procs(replay.source().blocks());
+
+        let mut debugger = Self {
+            replay,
+            history: Vec::new(),
+            cursor: 0,
+            mem_log: Vec::new(),
+            memory: Memory::default(),
+            mem_changes: Vec::new(),
+            mem_changes_applied: 0,
+            pending_mem_changes: Vec::new(),
+            block_procs,
+            call_stack: Rc::default(),
+            pending_entered: false,
+            pending_notes: Vec::new(),
+            finished: false,
+            error: None,
+        };
+        debugger.read_ahead();
+        debugger
+    }
+
+    /// The parsed source blocks being replayed.
+    pub fn blocks(&self) -> &SourceBlocks {
+        self.replay.source().blocks()
+    }
+
+    /// The step we're at, if the replay has any steps at all.
+    pub fn current(&self) -> Option<&Step> {
+        self.history.get(self.cursor)
+    }
+
+    /// The index of the step we're at.
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    /// Every step reached so far.
+    pub fn steps(&self) -> &[Step] {
+        &self.history
+    }
+
+    /// Whether we're at the final step of the replay.
+    pub fn at_end(&self) -> bool {
+        self.finished && self.cursor + 1 >= self.history.len()
+    }
+
+    /// The error which ended the replay, if any.
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+
+    /// The procedure which the block is within.
+    pub fn proc_of(&self, block: BlockKey) -> Option<BlockKey> {
+        self.block_procs.get(&block).copied()
+    }
+
+    /// The memory as modelled from the trace, as of the current step.
+    pub fn memory(&self) -> &Memory {
+        &self.memory
+    }
+
+    /// The memory words reported as accessed by the current step, as (base address, words).
+    pub fn memory_accesses(&self) -> impl Iterator<Item = (u64, [Option<u64>; 4])> + '_ {
+        let start = self
+            .mem_log
+            .partition_point(|(step_idx, ..)| *step_idx < self.cursor);
+        self.mem_log[start..]
+            .iter()
+            .take_while(|(step_idx, ..)| *step_idx == self.cursor)
+            .map(|(_, base_addr, words)| (*base_addr, *words))
+    }
+
+    /// Step forward one op.  Returns false if already at the end.
+    pub fn step(&mut self) -> bool {
+        if self.cursor + 1 >= self.history.len() {
+            return false;
+        }
+        self.cursor += 1;
+        self.read_ahead();
+        true
+    }
+
+    /// Step back one op.  Returns false if already at the start.
+    pub fn step_back(&mut self) -> bool {
+        if self.cursor == 0 {
+            return false;
+        }
+        self.cursor -= 1;
+        self.seek_memory();
+        true
+    }
+
+    /// Step forward one op, running over any procedure called.
+    pub fn step_over(&mut self) -> bool {
+        let depth = self.call_depth();
+        self.step_until(|step| step.call_stack.len() <= depth)
+    }
+
+    /// Run until returning from the current procedure.
+    pub fn step_out(&mut self) -> bool {
+        let depth = self.call_depth();
+        self.step_until(|step| step.call_stack.len() < depth)
+    }
+
+    /// Run forwards or backwards to the first step at or after the trace cycle.
+    pub fn run_to_cycle(&mut self, cycle: usize) -> bool {
+        while self.cursor > 0 && self.history[self.cursor - 1].cycle >= cycle {
+            self.cursor -= 1;
+        }
+        self.seek_memory();
+        if self.current().is_some_and(|step| step.cycle >= cycle) {
+            return true;
+        }
+
+        self.step_until(|step| step.cycle >= cycle)
+    }
+
+    /// Run to the end of the replay.
+    pub fn run_to_end(&mut self) {
+        while self.step() {}
+    }
+
+    /// Step forwards until the predicate holds for the current step, or the replay ends.
+    pub fn step_until(&mut self, pred: impl Fn(&Step) -> bool) -> bool {
+        while self.step() {
+            if self.current().is_some_and(&pred) {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Step backwards until the predicate holds for the current step, or the start is reached.
+    pub fn step_back_until(&mut self, pred: impl Fn(&Step) -> bool) -> bool {
+        while self.step_back() {
+            if self.current().is_some_and(&pred) {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn call_depth(&self) -> usize {
+        self.current()
+            .map(|step| step.call_stack.len())
+            .unwrap_or_default()
+    }
+
+    // Keep a step beyond the cursor, so everything which happened with the current step, e.g.,
+    // its memory accesses, is known.
+    fn read_ahead(&mut self) {
+        while self.cursor + 1 >= self.history.len() && self.pull_step() {}
+        self.seek_memory();
+    }
+
+    // Make or undo the memory changes so the memory is as of the cursor.
+    fn seek_memory(&mut self) {
+        let applied = self
+            .mem_changes
+            .partition_point(|(step_idx, _)| *step_idx <= self.cursor);
+        while self.mem_changes_applied < applied {
+            self.memory
+                .apply(&self.mem_changes[self.mem_changes_applied].1, false);
+            self.mem_changes_applied += 1;
+        }
+        while self.mem_changes_applied > applied {
+            self.mem_changes_applied -= 1;
+            self.memory
+                .apply(&self.mem_changes[self.mem_changes_applied].1, true);
+        }
+    }
+
+    // The pending memory changes made by the latest step's cycles, up to the trace index, belong
+    // to it.
+    fn take_mem_changes(&mut self, cycle: usize) {
+        let step_idx = self.history.len().saturating_sub(1);
+        let (changes, pending) = std::mem::take(&mut self.pending_mem_changes)
+            .into_iter()
+            .partition(|change| change.cycle <= cycle);
+        self.pending_mem_changes = pending;
+        self.mem_changes.extend(
+            changes
+                .into_iter()
+                .map(|change: FeltChange| (step_idx, change)),
+        );
+    }
+
+    // Run the replay until it has produced another step.
+    fn pull_step(&mut self) -> bool {
+        while !self.finished {
+            let event = match self.replay.next() {
+                Some(Ok(event)) => event,
+                Some(Err(err)) => {
+                    self.error = Some(err.to_string());
+                    self.finished = true;
+                    break;
+                }
+                None => {
+                    self.finished = true;
+                    break;
+                }
+            };
+            self.pending_mem_changes
+                .extend(self.replay.take_memory_changes());
+
+            match event {
+                ReplayEvent::Entry { func } => {
+                    self.call_stack = Rc::new(vec![Frame {
+                        func,
+                        call_site: None,
+                    }]);
+                    self.pending_entered = true;
+                }
+                ReplayEvent::Enter { pos, callee, .. } => {
+                    Rc::make_mut(&mut self.call_stack).push(Frame {
+                        func: callee,
+                        call_site: Some(pos),
+                    });
+                    self.pending_entered = true;
+                }
+                ReplayEvent::Return { .. } => {
+                    Rc::make_mut(&mut self.call_stack).pop();
+                }
+
+                ReplayEvent::OpExecuted {
+                    func,
+                    op,
+                    pos,
+                    cycle,
+                    stack,
+                    ..
+                } => {
+                    self.push_step(func, op, pos, cycle, stack);
+                    self.take_mem_changes(cycle);
+                    return true;
+                }
+
+                ReplayEvent::Skipped {
+                    ref op,
+                    pos,
+                    ref callee,
+                    ..
+                } => {
+                    let (cycle, stack) = self
+                        .history
+                        .last()
+                        .map(|step| (step.cycle, step.stack.clone()))
+                        .unwrap_or_default();
+                    let func = self
+                        .call_stack
+                        .last()
+                        .map(|frame| frame.func.clone())
+                        .unwrap_or_default();
+                    self.pending_notes.push(format!("Skipped {callee}."));
+                    self.push_step(func, op.clone(), pos, cycle, stack);
+                    return true;
+                }
+
+                ReplayEvent::MemoryAccess {
+                    base_addr, words, ..
+                } => {
+                    let step_idx = self.history.len().saturating_sub(1);
+                    self.mem_log.push((step_idx, base_addr, words));
+                }
+
+                // A skipped call's cycles are those of the step skipping it.
+                ReplayEvent::SkippedCycles { cycle, .. } => self.take_mem_changes(cycle),
+
+                // Nothing interesting to say about these.
+                ReplayEvent::BranchTaken { .. }
+                | ReplayEvent::Iterate { .. }
+                | ReplayEvent::BlockEnd { .. } => {}
+
+                ReplayEvent::Failure { ref report, .. } => {
+                    if let Some(step) = self.history.last_mut() {
+                        step.failure = Some(report.message.clone());
+                    }
+                    self.note(&event);
+                }
+
+                event => self.note(&event),
+            }
+        }
+
+        self.take_mem_changes(usize::MAX);
+        false
+    }
+
+    fn push_step(&mut self, func: String, op: Op, pos: SourcePos, cycle: usize, stack: Vec<u64>) {
+        self.history.push(Step {
+            func,
+            op,
+            pos,
+            cycle,
+            stack,
+            call_stack: self.call_stack.clone(),
+            entered: std::mem::take(&mut self.pending_entered),
+            notes: std::mem::take(&mut self.pending_notes),
+            failure: None,
+        });
+    }
+
+    // Attach the event, as text, to the latest step.
+    fn note(&mut self, event: &ReplayEvent) {
+        let mut renderer = TextRenderer::new(Vec::new());
+        if renderer.render(event).is_err() {
+            return;
+        }
+        let text = String::from_utf8_lossy(&renderer.into_inner())
+            .trim()
+            .to_string();
+
+        match self.history.last_mut() {
+            Some(step) => step.notes.push(text),
+            None => self.pending_notes.push(text),
+        }
+    }
+}
+
+/// A procedure's source as lines of text, with the position of the op on each line.  Lines which
+/// aren't ops, i.e., `else` and `end`, have no position.
+pub fn proc_listing(blocks: &SourceBlocks, proc_key: BlockKey) -> Vec<(Option<SourcePos>, String)> {
+    blocks
+        .lines(proc_key)
+        .into_iter()
+        .map(|line| match line {
+            SourceLine::Op {
+                block,
+                pc,
+                op,
+                depth,
+            } => (
+                Some(SourcePos { block, pc }),
+                format!("{}{op}", indent(depth)),
+            ),
+            SourceLine::Else { depth } => (None, format!("{}else", indent(depth))),
+            SourceLine::End { depth } => (None, format!("{}end", indent(depth))),
+        })
+        .collect()
+}
+
+fn indent(depth: usize) -> String {
+    " ".repeat(depth * src_mgr::INDENT_AMOUNT)
+}
+
+// Map every block with ops to the procedure it's within.
+fn block_procs(blocks: &SourceBlocks) -> fxhash::FxHashMap<BlockKey, BlockKey> {
+    let mut procs = fxhash::FxHashMap::default();
+    for (proc_key, block) in blocks.iter() {
+        if block.name().is_some() {
+            procs.insert(proc_key, proc_key);
+            for line in blocks.lines(proc_key) {
+                if let SourceLine::Op { block, .. } = line {
+                    procs.insert(block, proc_key);
+                }
+            }
+        }
+    }
+    procs
+}
+
+// vim:fdl=3
@@ -1,88 +1,27 @@
 // Typically the mangled names in these logs are weird, as the module name is not mangled, just the
-// function path.  So we can search along until we hit a recognised mangle token and go from there.
-
-pub(crate) fn demangle(mangled: &str) -> String {
-    let mut demangled = String::with_capacity(mangled.len());
-
-    enum ParseState {
-        InPrefix,
-        InPrefixWithUnderscore,
-        AtMangleStart,
-        AtPathStart,
-        InPathElement,
-        AtEnd,
-    }
-
-    let mut state = ParseState::InPrefix;
-    let mut path_element = String::default();
-    let mut path_element_count = 0_u32;
-
-    for c in mangled.chars() {
-        match state {
-            ParseState::InPrefix => {
-                if c == '_' {
-                    state = ParseState::InPrefixWithUnderscore;
-                } else {
-                    demangled.push(c);
-                }
-            }
-
-            ParseState::InPrefixWithUnderscore => {
-                if c == 'Z' {
-                    state = ParseState::AtMangleStart;
-                } else {
-                    demangled.push('_');
-                    demangled.push(c);
-                    state = ParseState::InPrefix;
-                }
-            }
-
-            ParseState::AtMangleStart => {
-                if c == 'N' {
-                    state = ParseState::AtPathStart;
-                } else {
-                    todo!("Unexpected non-appearance of 'N' after '_Z'");
-                }
-            }
-
-            ParseState::AtPathStart => {
-                if let Some(n) = c.to_digit(10) {
-                    path_element_count = path_element_count * 10 + n;
-                } else if c == 'E' {
-                    state = ParseState::AtEnd;
-                } else {
-                    if !path_element.is_empty() {
-                        if !demangled.ends_with("::") {
-                            demangled.push_str("::");
-                        }
-                        demangled.push_str(path_element.as_str());
-
-                        path_element.clear();
-                    }
-
-                    path_element.push(c);
-                    path_element_count -= 1;
-
-                    if path_element_count > 0 {
-                        state = ParseState::InPathElement;
-                    }
-                }
-            }
-
-            ParseState::InPathElement => {
-                path_element.push(c);
-                path_element_count -= 1;
-
-                if path_element_count == 0 {
-                    state = ParseState::AtPathStart;
-                }
-            }
-
-            ParseState::AtEnd => {
-                unreachable!("We shouldn't get characters after the end.");
-            }
-        }
-    }
+// function path.  So we can search along until we hit a path segment which starts with a recognised
+// mangle prefix and go from there.
+
+/// Demangle a Rust symbol, either legacy (`_ZN...E`) or v0 (`_R...`), which may be preceded by an
+/// unmangled module path.  The hashes and crate disambiguators are dropped.  Symbols which don't
+/// appear to be mangled are returned as is.
+pub fn demangle(symbol: &str) -> anyhow::Result<String> {
+    let Some(mangle_idx) = find_mangled_segment(symbol) else {
+        return Ok(symbol.to_string());
+    };
+
+    let (prefix, mangled) = symbol.split_at(mangle_idx);
+    let demangled = rustc_demangle::try_demangle(mangled)
+        .map_err(|_| anyhow::anyhow!("Failed to demangle symbol: {symbol}"))?;
+
+    Ok(format!("{prefix}{demangled:#}"))
+}
 
-    demangled
+fn find_mangled_segment(symbol: &str) -> Option<usize> {
+    std::iter::once(0)
+        .chain(symbol.match_indices("::").map(|(idx, sep)| idx + sep.len()))
+        .find(|idx| {
+            let segment = &symbol[*idx..];
+            segment.starts_with("_ZN") || segment.starts_with("__ZN") || segment.starts_with("_R")
+        })
 }
@@ -0,0 +1,233 @@
+use std::{collections::BTreeMap, io::Write};
+
+use crate::{
+    debugger::{Debugger, Step},
+    render, semantics,
+};
+
+// How many of the steps before a divergence are shown.
+const CONTEXT_STEPS: usize = 5;
+
+/// How two replays first disagree.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DivergenceKind {
+    /// Different procedures or ops were run.
+    Op,
+
+    /// The same op left different stacks.
+    Stack,
+
+    /// The same op accessed different memory, or found different values there.
+    Memory,
+
+    /// One replay ended before the other.
+    End,
+}
+
+/// The first point at which two replays of the same program disagree.
+#[derive(Debug)]
+pub struct Divergence {
+    pub kind: DivergenceKind,
+
+    /// The index of the step at which they diverge; every step before it matched.
+    pub step_idx: usize,
+
+    /// The diverging step of each replay, unless that replay had already ended.
+    pub steps: [Option<Step>; 2],
+
+    /// The matching steps leading up to the divergence, from the first replay.
+    pub context: Vec<Step>,
+
+    /// The memory known to each replay as of the divergence.
+    pub memory: [BTreeMap<u64, [Option<u64>; 4]>; 2],
+
+    /// The error which ended each replay, if any.
+    pub errors: [Option<String>; 2],
+}
+
+/// Replay both in lockstep, comparing each step, until they diverge or both end.  Returns `None`
+/// if they never diverge.
+pub fn diff(mut a: Debugger, mut b: Debugger) -> Option<Divergence> {
+    loop {
+        let kind = match (a.current(), b.current()) {
+            (None, None) => return None,
+            (Some(a_step), Some(b_step)) => compare_steps(a_step, b_step).or_else(|| {
+                (!a.memory_accesses().eq(b.memory_accesses())).then_some(DivergenceKind::Memory)
+            }),
+            _ => Some(DivergenceKind::End),
+        };
+
+        if let Some(kind) = kind {
+            return Some(divergence(kind, &a, &b, a.cursor()));
+        }
+
+        match (a.step(), b.step()) {
+            (true, true) => {}
+            (false, false) => return None,
+
+            // One has run out of steps; the other's next step is the divergence.
+            (a_stepped, _) => {
+                let step_idx = a.cursor().max(b.cursor());
+                let mut divergence = divergence(DivergenceKind::End, &a, &b, step_idx);
+                divergence.steps[usize::from(a_stepped)] = None;
+                return Some(divergence);
+            }
+        }
+    }
+}
+
+fn compare_steps(a_step: &Step, b_step: &Step) -> Option<DivergenceKind> {
+    if a_step.func != b_step.func || a_step.op != b_step.op || a_step.failure != b_step.failure {
+        Some(DivergenceKind::Op)
+    } else if top(&a_step.stack) != top(&b_step.stack) {
+        Some(DivergenceKind::Stack)
+    } else {
+        None
+    }
+}
+
+fn divergence(kind: DivergenceKind, a: &Debugger, b: &Debugger, step_idx: usize) -> Divergence {
+    Divergence {
+        kind,
+        step_idx,
+        steps: [a.current().cloned(), b.current().cloned()],
+        context: context(a, step_idx),
+        memory: [a.memory().words().collect(), b.memory().words().collect()],
+        errors: [a.error().map(str::to_string), b.error().map(str::to_string)],
+    }
+}
+
+fn context(debugger: &Debugger, step_idx: usize) -> Vec<Step> {
+    let steps = &debugger.steps()[..step_idx.min(debugger.steps().len())];
+    steps[steps.len().saturating_sub(CONTEXT_STEPS)..].to_vec()
+}
+
+impl Divergence {
+    /// Write a report of the divergence, naming the replays as given.
+    pub fn write_report(&self, names: [&str; 2], mut out: impl Write) -> anyhow::Result<()> {
+        let reason = match self.kind {
+            DivergenceKind::Op => "different ops were run",
+            DivergenceKind::Stack => "the stacks differ",
+            DivergenceKind::Memory => "the memory accessed differs",
+            DivergenceKind::End => "one replay ended early",
+        };
+        writeln!(out, "Diverged at step {}: {reason}.", self.step_idx)?;
+
+        // The call path, from each replay if they differ.
+        let call_paths = self.steps.each_ref().map(|step| {
+            step.as_ref()
+                .map(|step| {
+                    step.call_stack
+                        .iter()
+                        .map(|frame| frame.func.clone())
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_default()
+        });
+        if call_paths[0] == call_paths[1] || call_paths.iter().any(Vec::is_empty) {
+            let call_path = if call_paths[0].is_empty() {
+                &call_paths[1]
+            } else {
+                &call_paths[0]
+            };
+            write_call_path(&mut out, "Call path:", call_path)?;
+        } else {
+            for (name, call_path) in names.iter().zip(&call_paths) {
+                write_call_path(&mut out, &format!("Call path in {name}:"), call_path)?;
+            }
+        }
+
+        if !self.context.is_empty() {
+            writeln!(out)?;
+            writeln!(out, "Leading up to it:")?;
+            for step in &self.context {
+                write_step(&mut out, step)?;
+            }
+        }
+
+        for ((name, step), error) in names.iter().zip(&self.steps).zip(&self.errors) {
+            writeln!(out)?;
+            match step {
+                Some(step) => {
+                    writeln!(out, "In {name}, at cycle {} in {}:", step.cycle, step.func)?;
+                    write_step(&mut out, step)?;
+                    if let Some(failure) = &step.failure {
+                        writeln!(out, "    FAILED: {failure}")?;
+                    }
+                }
+                None => writeln!(out, "In {name}, the replay has ended.")?,
+            }
+            if let Some(error) = error {
+                writeln!(out, "    Replay failed: {error}")?;
+            }
+        }
+
+        // The memory words known to either, showing both where they differ.
+        let mut base_addrs = self.memory[0]
+            .keys()
+            .chain(self.memory[1].keys())
+            .collect::<Vec<_>>();
+        base_addrs.sort();
+        base_addrs.dedup();
+        if !base_addrs.is_empty() {
+            writeln!(out)?;
+            writeln!(out, "Memory:")?;
+            for base_addr in base_addrs {
+                let words = self
+                    .memory
+                    .each_ref()
+                    .map(|memory| memory.get(base_addr).copied().unwrap_or_default());
+                if words[0] == words[1] {
+                    writeln!(
+                        out,
+                        "    {base_addr:0>8x}    {}",
+                        render::format_word(&words[0])
+                    )?;
+                } else {
+                    for (name, words) in names.iter().zip(&words) {
+                        writeln!(
+                            out,
+                            "    {base_addr:0>8x}    {}  {name}",
+                            render::format_word(words)
+                        )?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// The operand stack proper; the trace doesn't reliably show the overflow table.
+fn top(stack: &[u64]) -> &[u64] {
+    &stack[..stack.len().min(semantics::STACK_DEPTH)]
+}
+
+fn write_call_path(out: &mut impl Write, title: &str, call_path: &[String]) -> anyhow::Result<()> {
+    if call_path.is_empty() {
+        return Ok(());
+    }
+    writeln!(out)?;
+    writeln!(out, "{title}")?;
+    for func in call_path {
+        writeln!(out, "    {func}")?;
+    }
+    Ok(())
+}
+
+fn write_step(out: &mut impl Write, step: &Step) -> anyhow::Result<()> {
+    const STACK_OFFS: usize = 36;
+
+    let op = step.op.to_string();
+    let pad = STACK_OFFS.saturating_sub(op.len()).max(1);
+    writeln!(
+        out,
+        "    {op}{}{}",
+        " ".repeat(pad),
+        render::format_stack(top(&step.stack))
+    )?;
+    Ok(())
+}
+
+// vim:fdl=3
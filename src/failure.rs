@@ -0,0 +1,60 @@
+/// The report printed by the executor when the program fails, following the trace.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FailureReport {
+    /// The procedures on the call stack, outermost first.
+    pub stack_trace: Vec<String>,
+
+    /// The last few instructions executed in the failing procedure, oldest first.
+    pub last_instructions: Vec<String>,
+
+    /// The last few instructions executed in any procedure, oldest first.
+    pub last_instructions_any_frame: Vec<String>,
+
+    pub frame_pointer: Option<u64>,
+    pub operand_stack: Vec<u64>,
+
+    /// The VM step and cycle at which execution failed.
+    pub step: u64,
+    pub cycle: u64,
+
+    /// The reason for the failure, e.g., `assertion failed at clock cycle 3759 with error code: 0`.
+    pub message: String,
+}
+
+impl FailureReport {
+    /// The error code, for failed assertions.
+    pub fn error_code(&self) -> Option<&str> {
+        self.message
+            .rsplit_once("error code:")
+            .map(|(_, code)| code.trim())
+    }
+
+    /// Whether the reported stack trace agrees with `call_stack`.  The executor names procedures
+    /// by their exported names, so only the final part of each name is compared, and reports the
+    /// program's `init` procedure as an extra outermost frame, which isn't replayed.
+    pub fn matches_call_stack(&self, call_stack: &[String]) -> bool {
+        let reported = match self.stack_trace.split_first() {
+            Some((outer, inner))
+                if base_name(outer) == "init" && inner.len() == call_stack.len() =>
+            {
+                inner
+            }
+            _ => &self.stack_trace[..],
+        };
+
+        !reported.is_empty()
+            && reported.len() == call_stack.len()
+            && reported
+                .iter()
+                .zip(call_stack)
+                .all(|(reported, replayed)| base_name(reported) == base_name(replayed))
+    }
+}
+
+// The unqualified name of a procedure, e.g., `run` for `miden:base/note-script@1.0.0#run`.
+fn base_name(func: &str) -> &str {
+    let name = func.rsplit("::").next().unwrap_or(func);
+    name.rsplit('#').next().unwrap_or(name)
+}
+
+// vim:fdl=3
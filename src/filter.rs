@@ -0,0 +1,190 @@
+use crate::replay::ReplayEvent;
+
+/// Which of a replay's events to show.  Procedures are matched against the end of their names, as
+/// for the entry function.
+#[derive(Clone, Debug, Default)]
+pub struct FilterOptions {
+    /// Show nothing before this cycle, an index into the trace.
+    pub from_cycle: Option<usize>,
+
+    /// Show nothing after this cycle.
+    pub to_cycle: Option<usize>,
+
+    /// Only show what happens inside these procedures and their callees.
+    pub only: Vec<String>,
+
+    /// Collapse calls to these procedures into a single `Hidden` event.
+    pub hide: Vec<String>,
+
+    /// Collapse calls deeper than this, where the entry function is at depth 0.
+    pub max_depth: Option<usize>,
+}
+
+/// Filters the events of a replay down to those to be shown.  Every event must be passed
+/// through, so the filter can follow the call stack and cycle count.
+#[derive(Debug, Default)]
+pub struct Filter {
+    options: FilterOptions,
+    call_stack: Vec<String>,
+    cycle: usize,
+    stack: Vec<u64>,
+
+    // The call being hidden and the cycles it has taken so far.
+    hiding: Option<HiddenCall>,
+}
+
+#[derive(Debug)]
+struct HiddenCall {
+    event: ReplayEvent,
+    stack_depth: usize,
+    cycles: u64,
+    stack_before: Vec<u64>,
+}
+
+impl Filter {
+    pub fn new(options: FilterOptions) -> Self {
+        Self {
+            options,
+            ..Self::default()
+        }
+    }
+
+    /// The events to show in place of the event.
+    pub fn filter(&mut self, event: ReplayEvent) -> Vec<ReplayEvent> {
+        if let ReplayEvent::OpExecuted { cycle, stack, .. }
+        | ReplayEvent::SkippedCycles { cycle, stack, .. } = &event
+        {
+            self.cycle = *cycle;
+            self.stack.clone_from(stack);
+        }
+
+        // Returns are shown from within the procedure returned from.
+        let was_shown = self.in_only();
+        match &event {
+            ReplayEvent::Entry { func } => self.call_stack = vec![func.clone()],
+            ReplayEvent::Enter { callee, .. } => self.call_stack.push(callee.clone()),
+            ReplayEvent::Return { .. } => {
+                self.call_stack.pop();
+            }
+            _ => {}
+        }
+
+        let mut shown = Vec::new();
+        if let Some(hidden) = &mut self.hiding {
+            // A hidden skipped call ends with any event which isn't part of skipping it.
+            let returned = match hidden.event {
+                ReplayEvent::Enter { .. } => {
+                    matches!(event, ReplayEvent::Return { .. })
+                        && self.call_stack.len() < hidden.stack_depth
+                }
+                _ => !matches!(
+                    event,
+                    ReplayEvent::SkippedCycles { .. }
+                        | ReplayEvent::Simulated { .. }
+                        | ReplayEvent::MemoryAccess { .. }
+                        | ReplayEvent::MemoryWatch { .. }
+                        | ReplayEvent::MemoryMismatch { .. }
+                        | ReplayEvent::UninitRead { .. }
+                        | ReplayEvent::StackMismatch { .. }
+                ),
+            };
+            let ended = matches!(
+                event,
+                ReplayEvent::Entry { .. }
+                    | ReplayEvent::Mismatch { .. }
+                    | ReplayEvent::Failure { .. }
+                    | ReplayEvent::FunctionNotFound { .. }
+                    | ReplayEvent::EndOfTrace
+            );
+            if !returned && !ended {
+                if let ReplayEvent::OpExecuted { cycles, .. }
+                | ReplayEvent::SkippedCycles { cycles, .. } = &event
+                {
+                    hidden.cycles += cycles;
+                }
+                return shown;
+            }
+
+            let entered = matches!(hidden.event, ReplayEvent::Enter { .. });
+            if let Some(hidden) = self.hiding.take() {
+                shown.extend(self.collapse(hidden));
+            }
+            if returned && entered {
+                return shown;
+            }
+        }
+
+        let callee = match &event {
+            ReplayEvent::Enter { callee, .. } | ReplayEvent::Skipped { callee, .. } => Some(callee),
+            _ => None,
+        };
+        // Entered callees are already on the call stack.
+        let too_deep = matches!(event, ReplayEvent::Enter { .. })
+            && self
+                .options
+                .max_depth
+                .is_some_and(|max_depth| self.call_stack.len() > max_depth + 1);
+        if let Some(callee) = callee
+            && (too_deep || self.options.hide.iter().any(|func| callee.ends_with(func)))
+        {
+            self.hiding = Some(HiddenCall {
+                event,
+                stack_depth: self.call_stack.len(),
+                cycles: 0,
+                stack_before: self.stack.clone(),
+            });
+            return shown;
+        }
+
+        let in_only = match event {
+            ReplayEvent::Return { .. } => was_shown,
+            _ => self.in_only(),
+        };
+        if in_only && self.in_cycle_range() {
+            shown.push(event);
+        }
+        shown
+    }
+
+    // The single event showing a hidden call, if it's to be shown at all.
+    fn collapse(&self, hidden: HiddenCall) -> Option<ReplayEvent> {
+        let (ReplayEvent::Enter {
+            op, callee, depth, ..
+        }
+        | ReplayEvent::Skipped {
+            op, callee, depth, ..
+        }) = hidden.event
+        else {
+            return None;
+        };
+
+        (self.in_only() && self.in_cycle_range()).then_some(ReplayEvent::Hidden {
+            op,
+            callee,
+            cycles: hidden.cycles,
+            stack_before: hidden.stack_before,
+            stack_after: self.stack.clone(),
+            depth,
+        })
+    }
+
+    fn in_only(&self) -> bool {
+        self.options.only.is_empty()
+            || self
+                .call_stack
+                .iter()
+                .any(|caller| self.options.only.iter().any(|func| caller.ends_with(func)))
+    }
+
+    fn in_cycle_range(&self) -> bool {
+        self.options
+            .from_cycle
+            .is_none_or(|from_cycle| self.cycle >= from_cycle)
+            && self
+                .options
+                .to_cycle
+                .is_none_or(|to_cycle| self.cycle <= to_cycle)
+    }
+}
+
+// vim:fdl=3
@@ -0,0 +1,36 @@
+use std::io::{BufRead, BufReader, Read};
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// Open a log for reading.  A path of `-` reads from stdin.  Gzip and zstd compressed logs are
+/// detected by their magic numbers and decompressed on the fly.
+pub fn open_log(path: &str) -> anyhow::Result<Box<dyn BufRead>> {
+    let reader: Box<dyn Read> = if path == "-" {
+        Box::new(std::io::stdin().lock())
+    } else {
+        Box::new(
+            std::fs::File::open(path)
+                .map_err(|err| anyhow::anyhow!("Failed to open log {path}: {err}"))?,
+        )
+    };
+
+    decompress(BufReader::new(reader))
+}
+
+/// Wrap a reader with a decompressor if its contents are compressed.
+pub fn decompress<R: BufRead + 'static>(mut reader: R) -> anyhow::Result<Box<dyn BufRead>> {
+    let magic = reader.fill_buf()?;
+
+    if magic.starts_with(GZIP_MAGIC) {
+        Ok(Box::new(BufReader::new(
+            flate2::bufread::MultiGzDecoder::new(reader),
+        )))
+    } else if magic.starts_with(ZSTD_MAGIC) {
+        Ok(Box::new(BufReader::new(
+            zstd::stream::read::Decoder::with_buffer(reader)?,
+        )))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
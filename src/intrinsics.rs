@@ -0,0 +1,425 @@
+use std::fmt;
+
+use crate::{
+    memory::{self, Memory},
+    semantics,
+};
+
+const U32_MAX: u64 = u32::MAX as u64;
+
+// The most words `memcopy_words` is simulated for; anything longer is left to the trace.
+const MAX_COPY_WORDS: u64 = 1 << 16;
+
+/// A call to a compiler intrinsic or standard library procedure which isn't in the source, and so
+/// was skipped over, but has been simulated.  Results are top of the stack first.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SimulatedCall {
+    pub name: String,
+    pub args: Vec<(&'static str, u64)>,
+    pub results: Vec<u64>,
+}
+
+impl fmt::Display for SimulatedCall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}(", self.name)?;
+        for (idx, (name, val)) in self.args.iter().enumerate() {
+            let sep = if idx == 0 { "" } else { ", " };
+            write!(f, "{sep}{name}={val:#x}")?;
+        }
+        write!(f, ")")?;
+
+        match self.results.as_slice() {
+            [] => Ok(()),
+            [result] => write!(f, " -> {result:#x}"),
+            results => {
+                write!(f, " -> [")?;
+                for (idx, result) in results.iter().enumerate() {
+                    let sep = if idx == 0 { "" } else { ", " };
+                    write!(f, "{sep}{result:#x}")?;
+                }
+                write!(f, "]")
+            }
+        }
+    }
+}
+
+/// The modelled effect of a call, given the stack and memory before it.
+#[derive(Debug)]
+pub(crate) struct Simulation {
+    name: String,
+    args: Vec<(&'static str, u64)>,
+
+    // The results, top first, or `None` where they depend on unknown memory or advice.
+    results: Vec<Option<u64>>,
+
+    // The number of values taken from the stack.
+    inputs: usize,
+
+    /// The felts stored by the call, as (felt address, value), in order.
+    pub(crate) writes: Vec<(u64, u64)>,
+}
+
+impl Simulation {
+    /// The call, with its results as found on the traced stack after it.  They're checked against
+    /// the model by comparing with the `expected_stack()`.
+    pub(crate) fn call(&self, traced: &[u64]) -> SimulatedCall {
+        let results = (0..self.results.len())
+            .map(|idx| traced.get(idx).copied().unwrap_or_default())
+            .collect();
+        SimulatedCall {
+            name: self.name.clone(),
+            args: self.args.clone(),
+            results,
+        }
+    }
+
+    /// The stack expected after the call, given the stack before it.  Any results the model
+    /// doesn't know are taken from the traced stack.
+    pub(crate) fn expected_stack(&self, stack: &[u64], traced: &[u64]) -> Vec<u64> {
+        let mut expected = self.results_or(traced);
+        expected.extend(stack.get(self.inputs..).unwrap_or_default());
+        if expected.len() < semantics::STACK_DEPTH {
+            expected.resize(semantics::STACK_DEPTH, 0);
+        }
+        expected
+    }
+
+    fn results_or(&self, traced: &[u64]) -> Vec<u64> {
+        self.results
+            .iter()
+            .enumerate()
+            .map(|(idx, result)| {
+                result.unwrap_or_else(|| traced.get(idx).copied().unwrap_or_default())
+            })
+            .collect()
+    }
+}
+
+/// Simulate a call to `callee` given the stack, top first, and memory before it.  Returns `None`
+/// if there's no model of the procedure.
+pub(crate) fn simulate(callee: &str, stack: &[u64], memory: &Memory) -> Option<Simulation> {
+    let (module, name) = callee.rsplit_once("::")?;
+
+    let mut sim = Sim {
+        stack,
+        memory,
+        inputs: 0,
+        args: Vec::new(),
+        pushed: Vec::new(),
+        writes: Vec::new(),
+    };
+
+    match module {
+        "intrinsics::mem" => sim.mem(name)?,
+        "intrinsics::i32" => sim.int(name, 32)?,
+        "intrinsics::i64" => sim.int(name, 64)?,
+        "std::mem" => sim.std_mem(name)?,
+        _ => return None,
+    }
+
+    Some(Simulation {
+        name: name.to_string(),
+        args: sim.args,
+        results: sim.pushed.into_iter().rev().collect(),
+        inputs: sim.inputs,
+        writes: sim.writes,
+    })
+}
+
+/// Whether calls to `callee` are simulated.
+pub fn is_simulated(callee: &str) -> bool {
+    simulate(callee, &[], &Memory::default()).is_some()
+}
+
+struct Sim<'a> {
+    stack: &'a [u64],
+    memory: &'a Memory,
+    inputs: usize,
+    args: Vec<(&'static str, u64)>,
+
+    // The results in the order they're pushed, i.e., the top of the stack last.
+    pushed: Vec<Option<u64>>,
+    writes: Vec<(u64, u64)>,
+}
+
+impl Sim<'_> {
+    // The VM pads the stack with zeros, so popping never fails.
+    fn pop(&mut self) -> u64 {
+        let val = self.stack.get(self.inputs).copied().unwrap_or_default();
+        self.inputs += 1;
+        val
+    }
+
+    fn arg(&mut self, name: &'static str) -> u64 {
+        let val = self.pop();
+        self.args.push((name, val));
+        val
+    }
+
+    fn push(&mut self, val: Option<u64>) {
+        self.pushed.push(val);
+    }
+
+    // [addr, offset, ...]; the felt address and byte offset within it, shown as a byte address.
+    fn native_ptr(&mut self) -> (u64, u64) {
+        let (addr, offset) = (self.pop(), self.pop());
+        self.args
+            .push(("addr", memory::native_ptr_byte_addr(addr, offset)));
+        (addr, offset)
+    }
+
+    fn load(&self, addr: u64) -> Option<u64> {
+        self.writes
+            .iter()
+            .rev()
+            .find(|(write_addr, _)| *write_addr == addr)
+            .map(|(_, val)| *val)
+            .or_else(|| self.memory.get(addr))
+    }
+
+    fn store(&mut self, addr: u64, val: u64) {
+        self.writes.push((addr, val));
+    }
+
+    // A 32-bit value at a byte offset into the felt at `addr`.  Unaligned values span two felts,
+    // most significant byte first.
+    fn load_u32(&self, addr: u64, offset: u64) -> Option<u64> {
+        match offset {
+            0 => self.load(addr),
+            1..4 => {
+                let bits = offset * 8;
+                let (first, second) = (self.load(addr)?, self.load(addr + 1)?);
+                Some(((first << bits) | (second >> (32 - bits))) & U32_MAX)
+            }
+            _ => None,
+        }
+    }
+
+    // An unaligned store only updates the felts whose other bytes are known.
+    fn store_u32(&mut self, addr: u64, offset: u64, val: u64) {
+        match offset {
+            0 => self.store(addr, val),
+            1..4 => {
+                let bits = offset * 8;
+                if let Some(first) = self.load(addr) {
+                    self.store(addr, (first & !(U32_MAX >> bits) & U32_MAX) | (val >> bits));
+                }
+                if let Some(second) = self.load(addr + 1) {
+                    let val = (val << (32 - bits)) & U32_MAX;
+                    self.store(addr + 1, (second & (U32_MAX >> bits)) | val);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // The byte-addressed memory intrinsics.  Double words are stored high half first and are
+    // loaded with the high half on top.
+    fn mem(&mut self, name: &str) -> Option<()> {
+        match name {
+            "load_felt" => {
+                let (addr, _) = self.native_ptr();
+                let val = self.load(addr);
+                self.push(val);
+            }
+            "load_sw" => {
+                let (addr, offset) = self.native_ptr();
+                let val = self.load_u32(addr, offset);
+                self.push(val);
+            }
+            "load_dw" => {
+                let (addr, offset) = self.native_ptr();
+                let (hi, lo) = (self.load_u32(addr, offset), self.load_u32(addr + 1, offset));
+                self.push(lo);
+                self.push(hi);
+            }
+            "store_felt" => {
+                let (addr, _) = self.native_ptr();
+                let val = self.arg("value");
+                self.store(addr, val);
+            }
+            "store_sw" => {
+                let (addr, offset) = self.native_ptr();
+                let val = self.arg("value");
+                self.store_u32(addr, offset, val);
+            }
+            "store_dw" => {
+                let (addr, offset) = self.native_ptr();
+                let (hi, lo) = (self.pop(), self.pop());
+                self.args.push(("value", (hi << 32) | (lo & U32_MAX)));
+                self.store_u32(addr, offset, hi);
+                self.store_u32(addr + 1, offset, lo);
+            }
+            _ => return None,
+        }
+
+        Some(())
+    }
+
+    // The signed integer intrinsics, for 32 or 64 bit integers.  64 bit integers are a pair of
+    // u32 limbs, high limb on top.  Binary ops take `b` from the top of the stack and `a` below
+    // it, and compute `a op b`.
+    fn int(&mut self, name: &str, bits: u32) -> Option<()> {
+        let unary = |sim: &mut Self, f: fn(i128) -> i128, checked: bool| {
+            let a = sim.int_arg("a", bits);
+            let result = f(a);
+            sim.push_int(
+                bits,
+                (!checked || fits(result, bits)).then(|| wrap(result, bits)),
+            );
+        };
+        let binary = |sim: &mut Self| {
+            let b = sim.pop_int(bits);
+            let a = sim.pop_int(bits);
+            sim.args.push(("a", int_bits(a, bits)));
+            sim.args.push(("b", int_bits(b, bits)));
+            (a, b)
+        };
+
+        match name {
+            "is_signed" => {
+                let a = self.int_arg("a", bits);
+                self.push(Some(u64::from(a < 0)));
+            }
+            "unchecked_neg" => unary(self, |a| -a, false),
+            "checked_neg" => unary(self, |a| -a, true),
+
+            "wrapping_add" | "wrapping_sub" | "wrapping_mul" => {
+                let (a, b) = binary(self);
+                self.push_int(bits, Some(wrap(arith(name, a, b), bits)));
+            }
+            "checked_add" | "checked_sub" | "checked_mul" => {
+                let (a, b) = binary(self);
+                let result = arith(name, a, b);
+                self.push_int(bits, fits(result, bits).then_some(result));
+            }
+            "overflowing_add" | "overflowing_sub" | "overflowing_mul" => {
+                let (a, b) = binary(self);
+                let result = arith(name, a, b);
+                self.push_int(bits, Some(wrap(result, bits)));
+                self.push(Some(u64::from(!fits(result, bits))));
+            }
+            "checked_div" => {
+                let (a, b) = binary(self);
+                let result = a.checked_div(b).filter(|result| fits(*result, bits));
+                self.push_int(bits, result);
+            }
+
+            "is_lt" | "is_lte" | "is_gt" | "is_gte" => {
+                let (a, b) = binary(self);
+                let result = match name {
+                    "is_lt" => a < b,
+                    "is_lte" => a <= b,
+                    "is_gt" => a > b,
+                    _ => a >= b,
+                };
+                self.push(Some(u64::from(result)));
+            }
+            "min" | "max" => {
+                let (a, b) = binary(self);
+                self.push_int(bits, Some(if name == "min" { a.min(b) } else { a.max(b) }));
+            }
+
+            _ => return None,
+        }
+
+        Some(())
+    }
+
+    fn pop_int(&mut self, bits: u32) -> i128 {
+        if bits == 32 {
+            i128::from(self.pop() as u32 as i32)
+        } else {
+            let (hi, lo) = (self.pop() & U32_MAX, self.pop() & U32_MAX);
+            i128::from(((hi << 32) | lo) as i64)
+        }
+    }
+
+    fn int_arg(&mut self, name: &'static str, bits: u32) -> i128 {
+        let val = self.pop_int(bits);
+        self.args.push((name, int_bits(val, bits)));
+        val
+    }
+
+    fn push_int(&mut self, bits: u32, val: Option<i128>) {
+        let val = val.map(|val| int_bits(val, bits));
+        if bits == 32 {
+            self.push(val);
+        } else {
+            self.push(val.map(|val| val & U32_MAX));
+            self.push(val.map(|val| val >> 32));
+        }
+    }
+
+    // The standard library memory procedures.  The piped values come from the advice provider, so
+    // they're only known from the trace.
+    fn std_mem(&mut self, name: &str) -> Option<()> {
+        match name {
+            // [n, read_ptr, write_ptr, ...] -> [...]
+            "memcopy_words" => {
+                let num_words = self.arg("num_words");
+                let read_ptr = self.arg("read_ptr");
+                let write_ptr = self.arg("write_ptr");
+                for idx in 0..num_words.min(MAX_COPY_WORDS) * 4 {
+                    if let Some(val) = self.load(read_ptr + idx) {
+                        self.store(write_ptr + idx, val);
+                    }
+                }
+            }
+
+            // [num_words, write_ptr, ...] -> [C, B, A, write_ptr', ...]
+            "pipe_words_to_memory" => {
+                let num_words = self.arg("num_words");
+                let write_ptr = self.arg("write_ptr");
+                self.push(Some(write_ptr + num_words * 4));
+                (0..12).for_each(|_| self.push(None));
+            }
+
+            // [num_words, write_ptr, COM, ...] -> [write_ptr', ...]
+            "pipe_preimage_to_memory" => {
+                let num_words = self.arg("num_words");
+                let write_ptr = self.arg("write_ptr");
+                (0..4).for_each(|_| {
+                    self.pop();
+                });
+                self.push(Some(write_ptr + num_words * 4));
+            }
+
+            _ => return None,
+        }
+
+        Some(())
+    }
+}
+
+fn arith(name: &str, a: i128, b: i128) -> i128 {
+    match name.rsplit('_').next() {
+        Some("add") => a + b,
+        Some("sub") => a - b,
+        _ => a * b,
+    }
+}
+
+// Whether the value is representable as a signed integer of the given width.
+fn fits(val: i128, bits: u32) -> bool {
+    let limit = 1i128 << (bits - 1);
+    (-limit..limit).contains(&val)
+}
+
+// Truncate to a signed integer of the given width.
+fn wrap(val: i128, bits: u32) -> i128 {
+    let modulus = 1i128 << bits;
+    let val = val.rem_euclid(modulus);
+    if val >= modulus / 2 {
+        val - modulus
+    } else {
+        val
+    }
+}
+
+// The unsigned representation of a signed integer of the given width.
+fn int_bits(val: i128, bits: u32) -> u64 {
+    (wrap(val, bits).rem_euclid(1i128 << bits)) as u64
+}
+
+// vim:fdl=3
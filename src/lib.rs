@@ -0,0 +1,44 @@
+//! Parse the MASM source and executor trace from a Miden test log, then replay the trace against
+//! the source.
+//!
+//! ```no_run
+//! let (blocks, trace) = etp::parse_log(etp::open_log("test.log.gz")?)?;
+//!
+//! let replay = etp::Replay::new(blocks, trace, etp::ReplayOptions::default())?;
+//! let mut renderer = etp::TextRenderer::new(std::io::stdout());
+//! for event in replay {
+//!     etp::Renderer::render(&mut renderer, &event?)?;
+//! }
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+pub mod callgraph;
+pub mod chrome_trace;
+pub mod coverage;
+pub mod dap;
+pub mod debugger;
+pub mod demangle;
+pub mod diff;
+pub mod failure;
+pub mod filter;
+pub mod input;
+pub mod intrinsics;
+pub mod masm;
+pub mod memory;
+pub mod parser;
+pub mod profile;
+pub mod provenance;
+pub mod render;
+pub mod replay;
+mod resync;
+pub mod semantics;
+pub mod src_mgr;
+pub mod trace;
+pub mod tui;
+pub mod uninit;
+
+pub use input::open_log;
+pub use parser::{parse_log, parse_trace};
+pub use profile::Profiler;
+pub use render::{JsonRenderer, Renderer, TextRenderer};
+pub use replay::{Replay, ReplayEvent, ReplayOptions};
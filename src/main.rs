@@ -1,14 +1,137 @@
-mod demangle;
-mod masm;
-mod parser;
-mod src_mgr;
-mod trace;
+use std::io::Write;
 
 use clap::Parser;
+use etp::Renderer;
+
+#[derive(Clone, Copy, Debug, Default, clap::ValueEnum)]
+enum Format {
+    #[default]
+    Text,
+    Json,
+}
 
 #[derive(Debug, Parser)]
-#[command(version, about)]
+#[command(version, about, args_conflicts_with_subcommands = true)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    replay: ReplayArgs,
+
+    #[arg(
+        long,
+        help("Print a profile of the cycles spent in each procedure instead of the replay")
+    )]
+    profile: bool,
+
+    #[arg(
+        long,
+        value_name("FILE"),
+        help("Write collapsed stacks for a flamegraph to FILE")
+    )]
+    folded: Option<std::path::PathBuf>,
+
+    #[arg(
+        long,
+        value_name("FILE"),
+        help("Write the call graph to FILE, as JSON if it ends in .json or else Graphviz DOT")
+    )]
+    callgraph: Option<std::path::PathBuf>,
+
+    #[arg(
+        long,
+        value_name("FILE"),
+        help(
+            "Write a timeline of the procedures called to FILE, for chrome://tracing or Perfetto"
+        )
+    )]
+    chrome_trace: Option<std::path::PathBuf>,
+
+    #[arg(
+        long,
+        help("Print the source annotated with coverage instead of the replay")
+    )]
+    coverage: bool,
+
+    #[arg(
+        long,
+        help("Print the chain of ops which produced a stack value instead of the replay")
+    )]
+    explain_top: bool,
+
+    #[arg(
+        long,
+        requires("explain_top"),
+        help("Explain the stack as of this cycle rather than the end of the replay")
+    )]
+    cycle: Option<usize>,
+
+    #[arg(
+        long,
+        requires("explain_top"),
+        default_value_t,
+        help("Explain this stack slot rather than the top")
+    )]
+    slot: usize,
+
+    #[arg(
+        long,
+        value_name("CYCLE"),
+        help("Don't show the replay before this cycle")
+    )]
+    from_cycle: Option<usize>,
+
+    #[arg(
+        long,
+        value_name("CYCLE"),
+        help("Don't show the replay after this cycle")
+    )]
+    to_cycle: Option<usize>,
+
+    #[arg(
+        long,
+        value_name("FUNC"),
+        help("Only show the replay inside this procedure and its callees")
+    )]
+    only: Vec<String>,
+
+    #[arg(
+        long,
+        value_name("FUNC"),
+        help("Show calls to this procedure as a single line with its cycle count")
+    )]
+    hide: Vec<String>,
+
+    #[arg(
+        long,
+        value_name("N"),
+        help("Show calls more than N deep as a single line with their cycle count")
+    )]
+    max_depth: Option<usize>,
+
+    #[arg(short, long, value_enum, default_value_t, help("Output format"))]
+    format: Format,
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum Command {
+    /// Step through the replay interactively.
+    Tui(ReplayArgs),
+
+    /// Serve the Debug Adapter Protocol over stdio.
+    Dap,
+
+    /// Replay two logs of the same program and report where they first diverge.
+    Diff(DiffArgs),
+}
+
+#[derive(Debug, clap::Args)]
+struct ReplayArgs {
+    #[arg(
+        default_value("-"),
+        help("Log file, optionally gzip or zstd compressed, or '-' for stdin")
+    )]
     path: String,
 
     #[arg(short, long, help("Entry function symbol"))]
@@ -16,362 +139,227 @@ struct Cli {
 
     #[arg(short, long, help("Try again when returning from entry point"))]
     repeat: bool,
-}
 
-const SPACES: &str = "                                                                                                    ";
-
-fn spaces(count: usize) -> &'static str {
-    &SPACES[0..count]
+    #[arg(long, help("Don't demangle symbols from the trace"))]
+    raw_symbols: bool,
+
+    #[arg(
+        long,
+        help(
+            "Fail at the first mismatch rather than resynchronising, which is only tried up to \
+             the end of the mismatched block or the next call"
+        )
+    )]
+    strict: bool,
+
+    #[arg(
+        long,
+        help("Check each op's effect on the stack against the MASM semantics")
+    )]
+    check: bool,
+
+    #[arg(
+        long,
+        value_name("ADDR[..END]"),
+        value_parser(parse_addr_range),
+        help("Report each load and store of the felt address, or range of them")
+    )]
+    watch: Option<std::ops::Range<u64>>,
+
+    #[arg(
+        long,
+        help("List the loads of memory before it's written, by procedure, instead of the replay")
+    )]
+    uninit: bool,
 }
 
-fn main() -> anyhow::Result<()> {
-    let cli = Cli::parse();
+#[derive(Debug, clap::Args)]
+struct DiffArgs {
+    #[arg(help("Log of the expected execution, e.g., a passing test"))]
+    a_path: String,
 
-    let log_str = std::fs::read_to_string(cli.path)?;
-    let (src_items, trace) = parser::parse_trace(log_str.as_str())?;
+    #[arg(help("Log of the execution to compare with it"))]
+    b_path: String,
 
-    let mut srcs = src_mgr::SourceManager::new(src_items);
-    let mut demangled_symbols = fxhash::FxHashMap::default();
-    let mut mem_map = fxhash::FxHashMap::default();
-    let mut trace_idx = 0;
-    let mut prior_top_of_stack = [0, 0];
-    let mut pending_trace_skip = false;
-    let mut pending_print_mem = None;
+    #[arg(short, long, help("Entry function symbol"))]
+    entry_func: Option<String>,
 
-    let (entry_block, entry_func) = srcs.set_entry(&trace, &cli.entry_func)?;
+    #[arg(long, help("Don't demangle symbols from the trace"))]
+    raw_symbols: bool,
+}
 
-    println!("ENTRY AT {}", srcs.get_src_func_name()?);
+impl ReplayArgs {
+    fn replay(self) -> anyhow::Result<etp::Replay> {
+        let options = etp::ReplayOptions {
+            entry_func: self.entry_func,
+            repeat: self.repeat,
+            raw_symbols: self.raw_symbols,
+            strict: self.strict,
+            check_semantics: self.check,
+            watch: self.watch,
+            check_uninit: self.uninit,
+        };
 
-    if cli.entry_func.is_some() {
-        // We need to skip along the trace until we hit the entry.
-        while trace[trace_idx].func != entry_func {
-            trace_idx += 1;
-        }
+        replay(&self.path, options)
     }
+}
 
-    srcs.inc_indent();
-
-    'main_loop: loop {
-        // Synchronise the src mgr with the trace in the case of returning from blocks first.
-        if let Some(frame) = srcs.check_leave()? {
-            match frame {
-                src_mgr::BlockType::Start => {
-                    println!("RETURNED FROM ENTRY POINT");
-
-                    if cli.repeat && trace_idx < trace.len() {
-                        srcs.reset_entry(entry_block);
-                        pending_trace_skip = true;
-                        mem_map.clear();
-
-                        println!();
-                        println!("ENTRY AT {}", srcs.get_src_func_name()?);
-                        srcs.inc_indent();
-                    } else {
-                        break;
-                    }
-                }
-
-                src_mgr::BlockType::Exec => {
-                    println!("RETURN TO {} }}}}}}", srcs.get_src_func_name()?);
-                    println!();
-                }
-
-                src_mgr::BlockType::TrueBlock => {
-                    srcs.dec_indent();
-                    println!("{}else", spaces(srcs.indent()));
-                    println!("{}(SKIPPING)", spaces(srcs.indent_next()));
-                    println!("{}end", spaces(srcs.indent()));
-                }
-
-                src_mgr::BlockType::FalseBlock => {
-                    srcs.dec_indent();
-                    println!("{}end", spaces(srcs.indent()));
-                }
-            }
-
-            continue;
-        }
-
-        if pending_trace_skip {
-            // We need to skip the trace along until it arrives at the current function.
-            let ret_func_str = srcs.get_src_func_name()?;
-            loop {
-                perform_mem_io(&mut mem_map, trace.get(trace_idx), trace.get(trace_idx - 1));
-                trace_idx += 1;
-
-                if trace_idx >= trace.len() {
-                    println!("FUNCTION NOT FOUND");
-                    break 'main_loop;
-                }
-
-                let demangled_sym = demangled_symbols
-                    .entry(&trace[trace_idx].func)
-                    .or_insert_with(|| demangle::demangle(&trace[trace_idx].func));
-
-                if demangled_sym == ret_func_str {
-                    break;
-                }
-            }
-
-            pending_trace_skip = false;
-        }
-
-        if let Some(addr) = pending_print_mem {
-            print_mem(&mem_map, addr);
-            pending_print_mem = None;
-        }
-
-        perform_mem_io(&mut mem_map, trace.get(trace_idx), trace.get(trace_idx - 1));
-
-        let Some(trace::Trace {
-            func,
-            op,
-            cycle,
-            total,
-            stack,
-            ..
-        }) = trace.get(trace_idx)
-        else {
-            // End of trace.
-            assert!(trace_idx == trace.len());
-            break;
+impl DiffArgs {
+    fn run(self) -> anyhow::Result<()> {
+        let options = etp::ReplayOptions {
+            entry_func: self.entry_func,
+            raw_symbols: self.raw_symbols,
+            ..Default::default()
         };
+        let a = etp::debugger::Debugger::new(replay(&self.a_path, options.clone())?);
+        let b = etp::debugger::Debugger::new(replay(&self.b_path, options)?);
 
-        if cycle != total {
-            // Skip the intermediate micro-ops.
-            trace_idx += 1;
-            continue;
+        let mut out = std::io::stdout().lock();
+        match etp::diff::diff(a, b) {
+            Some(divergence) => divergence.write_report([&self.a_path, &self.b_path], &mut out),
+            None => Ok(writeln!(out, "No divergence.")?),
         }
+    }
+}
 
-        let src_op = srcs.get_src_op();
-
-        if src_op.opcode() == Some("trace") {
-            // Skip `trace` ops in the source; they're not in the actual trace.
-            srcs.next_op();
-            continue;
-        }
-
-        prior_top_of_stack[1] = prior_top_of_stack[0];
-        prior_top_of_stack[0] = stack[0];
-
-        // Usually the op just matches; we'll assume it's all lined up.
-        if src_op == op {
-            print_op(op, func, Some(stack), srcs.indent())?;
-
-            pending_print_mem = matches!(op, masm::Op::Op {
-                    opcode, ..
-                } if opcode == "mem_load" || opcode == "mem_store")
-            .then_some(prior_top_of_stack[1]);
-
-            srcs.next_op();
-            trace_idx += 1;
-
-            continue;
+// An address, or an exclusive range of them, in decimal or 0x-prefixed hex.
+fn parse_addr_range(arg: &str) -> Result<std::ops::Range<u64>, String> {
+    let parse_addr = |addr: &str| {
+        match addr.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16),
+            None => addr.parse(),
         }
+        .map_err(|err| format!("bad address '{addr}': {err}"))
+    };
 
-        // We have a mismatch; could be a call or conditional.
-        match src_op {
-            masm::Op::Op { opcode, arg } => {
-                if opcode == "exec" || opcode == "call" {
-                    print_op(src_op, func, None, srcs.indent())?;
-
-                    let callee_func_name =
-                        &arg.as_ref().expect("CALL/EXEC must have an argument")[2..];
-
-                    // Take note of memeory I/O.
-                    pending_print_mem = callee_func_name
-                        .starts_with("intrinsics::mem::")
-                        .then_some(prior_top_of_stack[1]);
-
-                    if let Some(callee_block_key) = srcs.find_block_key(callee_func_name) {
-                        // It seems maybe functions beginning with '__' are not actually run, or
-                        // traced, or... not sure.
-
-                        if callee_func_name
-                            .split("::")
-                            .last()
-                            .unwrap()
-                            .starts_with("__")
-                        {
-                            println!("{}(SKIPPING)", spaces(srcs.indent_next()));
-
-                            srcs.next_op();
-                        } else {
-                            srcs.enter(src_mgr::BlockType::Exec, callee_block_key);
-
-                            println!();
-                            println!("ENTERING {} {{{{{{", srcs.get_src_func_name()?);
-                        }
-                    } else {
-                        // Skip the unknown (probably intrinsic) function until it returns.
-                        println!("{}(SKIPPING)", spaces(srcs.indent_next()));
-
-                        // We could be at the end of a function, so the function we're actually
-                        // skipping to is not this one, but the caller.  So we need to know that
-                        // function name before we can skip.
-                        pending_trace_skip = true;
-
-                        // Skip the exec to unknown too.
-                        srcs.next_op();
-                    }
-                } else {
-                    println!();
-                    println!("src func: {}", srcs.get_src_func_name()?);
-                    println!("  src op {src_op:?}");
-                    println!("trace func: {func}");
-                    println!("  op {op:?}");
-
-                    anyhow::bail!("Mismatched operations!");
-                }
-            }
-
-            masm::Op::Conditional(t_block_key, f_block_key) => {
-                let cond = prior_top_of_stack[1] != 0;
-
-                let t_block_key = *t_block_key;
-                let f_block_key = *f_block_key;
-
-                println!("{}if.true", spaces(srcs.indent()));
-                if !cond {
-                    println!("{}(SKIPPING)", spaces(srcs.indent_next()));
-                    println!("{}else", spaces(srcs.indent()));
-
-                    srcs.inc_indent();
-                    srcs.enter(src_mgr::BlockType::FalseBlock, f_block_key);
-                } else {
-                    srcs.inc_indent();
-                    srcs.enter(src_mgr::BlockType::TrueBlock, t_block_key);
-                }
-            }
+    match arg.split_once("..") {
+        Some((start, end)) => Ok(parse_addr(start)?..parse_addr(end)?),
+        None => {
+            let addr = parse_addr(arg)?;
+            Ok(addr..addr + 1)
         }
     }
+}
 
-    println!();
-    println!("END OF TRACE");
-
-    Ok(())
+fn replay(path: &str, options: etp::ReplayOptions) -> anyhow::Result<etp::Replay> {
+    let (src_items, trace) = etp::parse_log(etp::open_log(path)?)?;
+    etp::Replay::new(src_items, trace, options)
 }
 
-fn perform_mem_io(
-    mem_map: &mut fxhash::FxHashMap<u64, u64>,
-    mem_op_trace: Option<&trace::Trace>,
-    prior_trace: Option<&trace::Trace>,
-) {
-    let Some(mem_op_trace) = mem_op_trace else {
-        return;
-    };
+fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
 
-    let Some(prior_trace) = prior_trace else {
-        return;
-    };
+    match cli.command {
+        Some(Command::Tui(args)) => {
+            let debugger = etp::debugger::Debugger::new(args.replay()?);
+            return etp::tui::Tui::new(debugger).run();
+        }
+        Some(Command::Diff(args)) => return args.run(),
+        Some(Command::Dap) => {
+            let mut server =
+                etp::dap::DapServer::new(std::io::stdin().lock(), std::io::stdout().lock());
+            return server.run();
+        }
+        None => {}
+    }
 
-    if mem_op_trace.cycle != 1 {
-        return;
+    let uninit = cli.replay.uninit;
+    let mut replay = cli.replay.replay()?;
+    let stdout = std::io::stdout().lock();
+    let mut renderer: Box<dyn Renderer> = match cli.format {
+        Format::Text => Box::new(etp::TextRenderer::new(stdout)),
+        Format::Json => Box::new(etp::JsonRenderer::new(stdout)),
     };
 
-    if let masm::Op::Op { opcode, arg } = &mem_op_trace.op {
-        if opcode == "mem_load" {
-            let addr = arg
-                .as_ref()
-                .and_then(|a_str| a_str.parse::<u64>().ok())
-                .unwrap_or(prior_trace.stack[0]);
-            let loaded_val = mem_op_trace.stack[0];
-
-            if let Some(mem_val) = mem_map.get(&addr) {
-                if *mem_val != loaded_val {
-                    println!("WARNING: memory mismatch at addr {addr:x}:");
-                    println!("  Expecting {loaded_val:x}, found {mem_val:x}");
-                }
-            } else {
-                mem_map.insert(addr, loaded_val);
+    let mut profiler = (cli.profile || cli.folded.is_some()).then(etp::Profiler::default);
+    let mut callgraph = cli
+        .callgraph
+        .is_some()
+        .then(etp::callgraph::CallGraph::default);
+    let mut chrome_trace = cli
+        .chrome_trace
+        .is_some()
+        .then(etp::chrome_trace::ChromeTrace::default);
+    let mut provenance = cli.explain_top.then(etp::provenance::Provenance::default);
+    let mut uninit_reads = uninit.then(etp::uninit::UninitReads::default);
+    let show_replay = !cli.profile && !cli.coverage && !cli.explain_top && !uninit;
+
+    let mut filter = etp::filter::Filter::new(etp::filter::FilterOptions {
+        from_cycle: cli.from_cycle,
+        to_cycle: cli.to_cycle,
+        only: cli.only,
+        hide: cli.hide,
+        max_depth: cli.max_depth,
+    });
+
+    for event in replay.by_ref() {
+        let event = event?;
+        if let Some(provenance) = &mut provenance {
+            if let (etp::ReplayEvent::OpExecuted { cycle, .. }, Some(last_cycle)) =
+                (&event, cli.cycle)
+                && *cycle > last_cycle
+            {
+                break;
             }
+            provenance.record(&event);
         }
-
-        if opcode == "mem_store" {
-            let (addr, val) = if let Some(addr_str) = arg {
-                (addr_str.parse::<u64>().unwrap(), prior_trace.stack[0])
-            } else {
-                (prior_trace.stack[0], prior_trace.stack[1])
-            };
-
-            mem_map.insert(addr, val);
+        if let Some(profiler) = &mut profiler {
+            profiler.record(&event);
+        }
+        if let Some(uninit_reads) = &mut uninit_reads {
+            uninit_reads.record(&event);
+        }
+        if let Some(callgraph) = &mut callgraph {
+            callgraph.record(&event);
+        }
+        if let Some(chrome_trace) = &mut chrome_trace {
+            chrome_trace.record(&event);
+        }
+        if show_replay {
+            for event in filter.filter(event) {
+                renderer.render(&event)?;
+            }
         }
     }
-}
-
-fn print_op(op: &masm::Op, func: &str, stack: Option<&[u64]>, indent: usize) -> anyhow::Result<()> {
-    use std::fmt::Write;
+    drop(renderer);
 
-    const STACK_INDENT_OFFS: usize = 40;
-
-    let masm::Op::Op { opcode, arg } = op else {
-        unreachable!("Unexpected non-regular op in {func} ({op:?})",);
-    };
-
-    let mut out_str = String::default();
-
-    // Print opcode.
-    write!(out_str, "{}{opcode}", spaces(indent))?;
-    if let Some(arg) = arg {
-        write!(out_str, ".{arg}")?;
+    if let Some(profiler) = profiler {
+        if cli.profile {
+            profiler.write_report(std::io::stdout().lock())?;
+        }
+        if let Some(folded_path) = &cli.folded {
+            let file = std::fs::File::create(folded_path)?;
+            profiler.write_folded(std::io::BufWriter::new(file))?;
+        }
     }
 
-    if let Some(stack) = stack {
-        // Pad out to the stack.
-        let stack_pad = if out_str.len() >= STACK_INDENT_OFFS {
-            // Nah, put the stack on the next line.
-            println!("{out_str}");
-            out_str.clear();
-
-            STACK_INDENT_OFFS
+    if let (Some(callgraph), Some(callgraph_path)) = (callgraph, &cli.callgraph) {
+        let out = std::io::BufWriter::new(std::fs::File::create(callgraph_path)?);
+        if callgraph_path.extension().is_some_and(|ext| ext == "json") {
+            callgraph.write_json(out)?;
         } else {
-            STACK_INDENT_OFFS - out_str.len()
-        };
-        write!(out_str, "{}", spaces(stack_pad))?;
-
-        // Print the stack.  Find the index to the last non-zero value first.
-        let nz_idx = stack
-            .iter()
-            .rev()
-            .position(|item| *item != 0)
-            .unwrap_or(stack.len());
-        let num_items_to_print = (stack.len() + 2 - nz_idx).min(stack.len());
-
-        write!(out_str, "[")?;
-        for el in stack.iter().take(num_items_to_print) {
-            if *el < 256 {
-                // Decimal.
-                write!(out_str, " {el}")?;
-            } else {
-                // Hex.
-                write!(out_str, " {el:x}h")?;
-            }
+            callgraph.write_dot(out)?;
         }
-        if num_items_to_print < stack.len() {
-            write!(out_str, " ...")?;
-        }
-        write!(out_str, " ]")?;
     }
 
-    println!("{out_str}");
+    if let (Some(chrome_trace), Some(chrome_trace_path)) = (chrome_trace, &cli.chrome_trace) {
+        let file = std::fs::File::create(chrome_trace_path)?;
+        chrome_trace.write(std::io::BufWriter::new(file))?;
+    }
 
-    Ok(())
-}
+    if let Some(uninit_reads) = uninit_reads {
+        uninit_reads.write_report(std::io::stdout().lock())?;
+    }
 
-fn print_mem(mem_map: &fxhash::FxHashMap<u64, u64>, addr: u64) {
-    // Print the 4 words surrounding the address.
-    let base_addr = addr - (addr % 4);
-    println!();
-    print!("| {base_addr:0>8x}: ");
-    for addr_idx in base_addr..base_addr + 4 {
-        if let Some(mem_val) = mem_map.get(&addr_idx) {
-            print!(" {mem_val:0>16x}");
-        } else {
-            print!("  ????????????????")
-        }
+    if let Some(provenance) = provenance {
+        provenance.write_explanation(cli.slot, std::io::stdout().lock())?;
+    }
+
+    if cli.coverage {
+        replay
+            .coverage()
+            .write_report(replay.source().blocks(), std::io::stdout().lock())?;
     }
-    println!(" |");
-    println!();
-}
 
-// vim:fdl=3
+    Ok(())
+}
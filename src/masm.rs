@@ -1,7 +1,9 @@
-slotmap::new_key_type! { pub(crate) struct BlockKey; }
+slotmap::new_key_type! { pub struct BlockKey; }
 
+/// All the blocks of source parsed from a log.  Procedures are named blocks, the bodies of
+/// conditionals and loops are bare blocks referred to by their parent's ops.
 #[derive(Debug, Default)]
-pub(crate) struct SourceBlocks(slotmap::SlotMap<BlockKey, Block>);
+pub struct SourceBlocks(slotmap::SlotMap<BlockKey, Block>);
 
 impl std::ops::Deref for SourceBlocks {
     type Target = slotmap::SlotMap<BlockKey, Block>;
@@ -17,8 +19,68 @@ impl std::ops::DerefMut for SourceBlocks {
     }
 }
 
+impl SourceBlocks {
+    /// The lines of the block's source in order, including those of the blocks nested within it.
+    pub fn lines(&self, block_key: BlockKey) -> Vec<SourceLine<'_>> {
+        let mut lines = Vec::new();
+        self.push_lines(block_key, 0, &mut lines);
+        lines
+    }
+
+    fn push_lines<'a>(
+        &'a self,
+        block_key: BlockKey,
+        depth: usize,
+        lines: &mut Vec<SourceLine<'a>>,
+    ) {
+        for (pc, op) in self[block_key].ops().iter().enumerate() {
+            lines.push(SourceLine::Op {
+                block: block_key,
+                pc,
+                op,
+                depth,
+            });
+
+            match op {
+                Op::Op { .. } => continue,
+                Op::Conditional {
+                    then_block,
+                    else_block,
+                    ..
+                } => {
+                    self.push_lines(*then_block, depth + 1, lines);
+                    if let Some(else_block) = else_block {
+                        lines.push(SourceLine::Else { depth });
+                        self.push_lines(*else_block, depth + 1, lines);
+                    }
+                }
+                Op::Loop(body) | Op::Repeat(_, body) => self.push_lines(*body, depth + 1, lines),
+            }
+            lines.push(SourceLine::End { depth });
+        }
+    }
+}
+
+/// A line of source, as nested within a procedure at `depth`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SourceLine<'a> {
+    /// The op at `pc` in the block.
+    Op {
+        block: BlockKey,
+        pc: usize,
+        op: &'a Op,
+        depth: usize,
+    },
+
+    /// The `else` of a conditional.
+    Else { depth: usize },
+
+    /// The `end` of a conditional or loop.
+    End { depth: usize },
+}
+
 #[derive(Debug)]
-pub(crate) struct Block {
+pub struct Block {
     name: Option<String>,
     ops: Vec<Op>,
 }
@@ -43,31 +105,73 @@ impl Block {
         }
     }
 
-    pub(crate) fn name(&self) -> Option<&String> {
+    pub fn name(&self) -> Option<&String> {
         self.name.as_ref()
     }
 
-    pub(crate) fn op_at(&self, idx: usize) -> &Op {
+    pub fn ops(&self) -> &[Op] {
+        &self.ops
+    }
+
+    pub fn op_at(&self, idx: usize) -> &Op {
         &self.ops[idx]
     }
 
-    pub(crate) fn len(&self) -> usize {
+    pub fn len(&self) -> usize {
         self.ops.len()
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
 }
 
-#[derive(Debug, PartialEq)]
-pub(crate) enum Op {
-    Op { opcode: String, arg: Option<String> },
-    Conditional(BlockKey, BlockKey),
+#[derive(Clone, Debug, PartialEq)]
+#[allow(clippy::enum_variant_names)]
+pub enum Op {
+    Op {
+        opcode: String,
+        arg: Option<String>,
+    },
+    Conditional {
+        /// Whether the `then` arm is taken on a true (`if.true`) or false (`if.false`) condition.
+        on_true: bool,
+        then_block: BlockKey,
+        else_block: Option<BlockKey>,
+    },
+    Loop(BlockKey),
+    Repeat(usize, BlockKey),
 }
 
 impl Op {
-    pub(crate) fn opcode(&self) -> Option<&str> {
+    pub fn opcode(&self) -> Option<&str> {
         if let Op::Op { opcode, .. } = self {
             Some(opcode)
         } else {
             None
         }
     }
+
+    pub fn arg(&self) -> Option<&str> {
+        if let Op::Op { arg, .. } = self {
+            arg.as_deref()
+        } else {
+            None
+        }
+    }
+}
+
+impl std::fmt::Display for Op {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Op::Op { opcode, arg: None } => write!(f, "{opcode}"),
+            Op::Op {
+                opcode,
+                arg: Some(arg),
+            } => write!(f, "{opcode}.{arg}"),
+            Op::Conditional { on_true, .. } => write!(f, "if.{on_true}"),
+            Op::Loop(_) => write!(f, "while.true"),
+            Op::Repeat(count, _) => write!(f, "repeat.{count}"),
+        }
+    }
 }
@@ -0,0 +1,293 @@
+use std::collections::VecDeque;
+
+use crate::{replay::ReplayEvent, trace};
+
+/// The number of bytes packed into each felt by the byte-addressed intrinsics.
+pub const BYTES_PER_FELT: u64 = 4;
+
+/// The intrinsics which take a native pointer on the top of the stack.
+const BYTE_ADDRESSED_INTRINSICS: &[&str] = &[
+    "intrinsics::mem::load_sw",
+    "intrinsics::mem::load_dw",
+    "intrinsics::mem::store_sw",
+    "intrinsics::mem::store_dw",
+];
+
+/// Whether memory was read or written.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MemoryOp {
+    Load,
+    Store,
+}
+
+/// A single felt loaded or stored by a traced VM op.  `old` is the value we believed was there
+/// and `new` that loaded or stored.  It was logged for being watched, or for being a load of a
+/// felt not yet written, or both.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct FeltAccess {
+    pub(crate) op: MemoryOp,
+    pub(crate) addr: u64,
+    pub(crate) old: Option<u64>,
+    pub(crate) new: u64,
+    pub(crate) watched: bool,
+    pub(crate) uninit: bool,
+}
+
+/// A change to a single felt of the model, as journaled for moving back and forth through it.
+/// `cycle` is the index into the trace of the cycle which made it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct FeltChange {
+    pub(crate) cycle: usize,
+    pub(crate) addr: u64,
+    pub(crate) old: Option<u64>,
+    pub(crate) new: Option<u64>,
+}
+
+/// A model of the VM memory, built up from the loads and stores seen in the trace.  Memory is
+/// addressed by felt and words are 4 consecutive felts, with the top of the stack at the highest
+/// address.
+#[derive(Debug, Default)]
+pub struct Memory {
+    felts: std::collections::BTreeMap<u64, u64>,
+
+    // The felt address of the most recent access by the current op.
+    last_access: Option<u64>,
+
+    // The felts which have been stored to, as opposed to only loaded from.
+    written: fxhash::FxHashSet<u64>,
+
+    // The felts to log accesses to, and whether to log loads of those not yet written.
+    watch: Option<std::ops::Range<u64>>,
+    log_uninit: bool,
+    accesses: Vec<FeltAccess>,
+
+    // The changes to the felts since last taken, if they're being journaled, and the trace index
+    // of the latest cycle performed.
+    journal: Option<Vec<FeltChange>>,
+    cycle: usize,
+}
+
+impl Memory {
+    /// A memory which logs accesses to the watched felts, and loads of felts not yet written.
+    pub(crate) fn with_logging(watch: Option<std::ops::Range<u64>>, log_uninit: bool) -> Self {
+        Self {
+            watch,
+            log_uninit,
+            ..Self::default()
+        }
+    }
+
+    /// The felt at `addr`, if it's known.
+    pub fn get(&self, addr: u64) -> Option<u64> {
+        self.felts.get(&addr).copied()
+    }
+
+    /// The known felts of the word containing `addr`.
+    pub fn word_at(&self, addr: u64) -> [Option<u64>; 4] {
+        let base_addr = word_base(addr);
+        std::array::from_fn(|idx| self.get(base_addr + idx as u64))
+    }
+
+    /// Every word with any known felts, as (base address, felts), in order of address.
+    pub fn words(&self) -> impl Iterator<Item = (u64, [Option<u64>; 4])> + '_ {
+        let mut prev_base_addr = None;
+        self.felts.keys().filter_map(move |&addr| {
+            let base_addr = word_base(addr);
+            (prev_base_addr.replace(base_addr) != Some(base_addr))
+                .then(|| (base_addr, self.word_at(base_addr)))
+        })
+    }
+
+    /// Whether no felts are known.
+    pub fn is_empty(&self) -> bool {
+        self.felts.is_empty()
+    }
+
+    /// Keep a journal of the changes to the felts, to be taken after each event.
+    pub(crate) fn keep_journal(&mut self) {
+        self.journal = Some(Vec::new());
+    }
+
+    /// The journaled changes since last taken.
+    pub(crate) fn take_changes(&mut self) -> Vec<FeltChange> {
+        self.journal
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    /// Make or undo a journaled change.
+    pub(crate) fn apply(&mut self, change: &FeltChange, undo: bool) {
+        let val = if undo { change.old } else { change.new };
+        match val {
+            Some(val) => self.felts.insert(change.addr, val),
+            None => self.felts.remove(&change.addr),
+        };
+    }
+
+    fn set(&mut self, addr: u64, val: Option<u64>) {
+        let old = match val {
+            Some(val) => self.felts.insert(addr, val),
+            None => self.felts.remove(&addr),
+        };
+        if let Some(journal) = &mut self.journal
+            && old != val
+        {
+            journal.push(FeltChange {
+                cycle: self.cycle,
+                addr,
+                old,
+                new: val,
+            });
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        let addrs = self.felts.keys().copied().collect::<Vec<_>>();
+        for addr in addrs {
+            self.set(addr, None);
+        }
+        self.written.clear();
+        self.last_access = None;
+    }
+
+    /// The felt address accessed by the current op, if any.
+    pub(crate) fn take_last_access(&mut self) -> Option<u64> {
+        self.last_access.take()
+    }
+
+    /// The logged accesses since last taken.
+    pub(crate) fn take_accesses(&mut self) -> Vec<FeltAccess> {
+        std::mem::take(&mut self.accesses)
+    }
+
+    /// Update the memory with the effect of a single traced VM op, at trace index `cycle`.  The
+    /// prior trace holds the stack as it was before the op.
+    pub(crate) fn perform_io(
+        &mut self,
+        events: &mut VecDeque<ReplayEvent>,
+        cycle: usize,
+        trace: Option<&trace::Trace>,
+        prior_trace: Option<&trace::Trace>,
+    ) {
+        self.cycle = cycle;
+        let (Some(trace), Some(prior_trace)) = (trace, prior_trace) else {
+            return;
+        };
+
+        // The VM stack is never shallower than 16, though the trace may be incomplete.
+        if trace.stack.len() < 16 || prior_trace.stack.len() < 16 {
+            return;
+        }
+
+        if trace.cycle == 1 {
+            // A new op; forget the accesses of the last.
+            self.last_access = None;
+        }
+
+        let addr = prior_trace.stack[0];
+        let (op, addr, vals) = match trace.vm_op.as_str() {
+            // [a, ...] -> [v, ...]
+            "MLoad" => (MemoryOp::Load, addr, &trace.stack[0..1]),
+
+            // [a, _, _, _, _, ...] -> [A, ...]
+            "MLoadW" => (MemoryOp::Load, addr, &trace.stack[0..4]),
+
+            // [a, v, ...] -> [v, ...]
+            "MStore" => (MemoryOp::Store, addr, &prior_trace.stack[1..2]),
+
+            // [a, A, ...] -> [A, ...]
+            "MStoreW" => (MemoryOp::Store, addr, &prior_trace.stack[1..5]),
+
+            // [C, B, A, a, ...] -> [E, D, A, a + 8, ...], with D and E from the advice provider.
+            "Pipe" => (MemoryOp::Store, prior_trace.stack[12], &trace.stack[0..8]),
+
+            // [C, B, A, a, ...] -> [E, D, A, a + 8, ...]
+            "MStream" => (MemoryOp::Load, prior_trace.stack[12], &trace.stack[0..8]),
+
+            _ => return,
+        };
+
+        // The stack holds words top first, but memory holds them top last.
+        let vals = vals.iter().rev().copied().collect::<Vec<_>>();
+        self.log_accesses(op, addr, &vals);
+        match op {
+            MemoryOp::Load => self.load(events, addr, &vals),
+            MemoryOp::Store => self.store(addr, &vals),
+        }
+    }
+
+    fn log_accesses(&mut self, op: MemoryOp, addr: u64, vals: &[u64]) {
+        for (addr, &new) in (addr..).zip(vals) {
+            let watched = self
+                .watch
+                .as_ref()
+                .is_some_and(|watch| watch.contains(&addr));
+            let uninit = self.log_uninit && op == MemoryOp::Load && !self.written.contains(&addr);
+            if watched || uninit {
+                self.accesses.push(FeltAccess {
+                    op,
+                    addr,
+                    old: self.get(addr),
+                    new,
+                    watched,
+                    uninit,
+                });
+            }
+        }
+    }
+
+    fn load(&mut self, events: &mut VecDeque<ReplayEvent>, addr: u64, loaded_vals: &[u64]) {
+        for (addr, &loaded) in (addr..).zip(loaded_vals) {
+            match self.felts.get(&addr) {
+                Some(&stored) if stored != loaded => {
+                    events.push_back(ReplayEvent::MemoryMismatch {
+                        addr,
+                        loaded,
+                        stored,
+                    });
+                }
+                Some(_) => {}
+                None => self.set(addr, Some(loaded)),
+            }
+        }
+
+        self.last_access = Some(addr);
+    }
+
+    pub(crate) fn store(&mut self, addr: u64, vals: &[u64]) {
+        for (addr, &val) in (addr..).zip(vals) {
+            self.set(addr, Some(val));
+            self.written.insert(addr);
+        }
+
+        self.last_access = Some(addr);
+    }
+
+    pub(crate) fn access_event(&self, addr: u64, byte_addr: u64) -> ReplayEvent {
+        ReplayEvent::MemoryAccess {
+            addr,
+            byte_addr,
+            base_addr: word_base(addr),
+            words: self.word_at(addr),
+        }
+    }
+}
+
+/// The address of the word containing the felt at `addr`.
+pub fn word_base(addr: u64) -> u64 {
+    addr - (addr % 4)
+}
+
+/// The byte address of a native pointer, as taken by the compiler intrinsics, which is a felt
+/// address and a byte offset within that felt.
+pub fn native_ptr_byte_addr(addr: u64, offset: u64) -> u64 {
+    addr * BYTES_PER_FELT + offset
+}
+
+/// Whether the procedure is an intrinsic taking a native pointer on the top of the stack.
+pub fn is_byte_addressed_intrinsic(func: &str) -> bool {
+    BYTE_ADDRESSED_INTRINSICS.contains(&func)
+}
+
+// vim:fdl=3
@@ -1,24 +1,115 @@
+use std::io::BufRead;
+
 use crate::{
+    failure::FailureReport,
     masm::{Block, BlockKey, Op, SourceBlocks},
     trace::Trace,
 };
 
-pub(crate) fn parse_trace(input: &str) -> anyhow::Result<(SourceBlocks, Vec<Trace>)> {
-    let mut blocks = SourceBlocks::default();
-    let trace = trace_parser::parse(input, &mut blocks).map_err(|err| {
-        let l = err.location.offset;
-        anyhow::anyhow!("{err}\nFound: {}...", &input[l..l + 20])
-    })?;
+const TRACE_MARKER: &str = "[TRACE executor]";
+
+// Where the line starts a trace event, with the marker then `in`, whatever whitespace separates
+// them.
+fn find_trace_in(line: &str) -> Option<usize> {
+    let marker_idx = line.find(TRACE_MARKER)?;
+    let rest = line[marker_idx + TRACE_MARKER.len()..].trim_start();
+    let after_in = rest.strip_prefix("in")?;
+    after_in
+        .starts_with(char::is_whitespace)
+        .then_some(marker_idx)
+}
+
+/// Parse the assembled MASM source and the executor trace events from a test log held in memory.
+pub fn parse_trace(input: &str) -> anyhow::Result<(SourceBlocks, Vec<Trace>)> {
+    let (blocks, trace_reader) = parse_log(input.as_bytes())?;
+    let trace = trace_reader.collect::<anyhow::Result<Vec<_>>>()?;
 
     Ok((blocks, trace))
 }
 
+/// Parse the assembled MASM source from the start of a log, stopping at the first trace event.
+/// The trace events are then parsed lazily by the returned reader, so the whole log need never be
+/// held in memory.
+pub fn parse_log<R: BufRead>(mut reader: R) -> anyhow::Result<(SourceBlocks, TraceReader<R>)> {
+    let mut src_str = String::new();
+    let mut line = String::new();
+    let next_item = loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            break None;
+        }
+
+        if let Some(marker_idx) = find_trace_in(&line) {
+            break Some(line[marker_idx..].to_string());
+        }
+
+        src_str.push_str(&line);
+    };
+
+    let mut blocks = SourceBlocks::default();
+    trace_parser::source(&src_str, &mut blocks).map_err(|err| parse_error(&src_str, err))?;
+
+    Ok((blocks, TraceReader { reader, next_item }))
+}
+
+/// Yields the trace events from a log, one at a time.
+pub struct TraceReader<R: BufRead> {
+    reader: R,
+
+    // The text gathered so far for the next event.
+    next_item: Option<String>,
+}
+
+impl<R: BufRead> TraceReader<R> {
+    // Gather the text for the next event, which is everything up to the start of the one after.
+    fn read_item(&mut self) -> anyhow::Result<Option<String>> {
+        let Some(mut item_str) = self.next_item.take() else {
+            return Ok(None);
+        };
+
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self.reader.read_line(&mut line)? == 0 {
+                break;
+            }
+
+            if let Some(marker_idx) = find_trace_in(&line) {
+                self.next_item = Some(line[marker_idx..].to_string());
+                break;
+            }
+
+            item_str.push_str(&line);
+        }
+
+        Ok(Some(item_str))
+    }
+}
+
+impl<R: BufRead> Iterator for TraceReader<R> {
+    type Item = anyhow::Result<Trace>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_item().transpose().map(|item_str| {
+            let item_str = item_str?;
+            trace_parser::trace(&item_str).map_err(|err| parse_error(&item_str, err))
+        })
+    }
+}
+
+fn parse_error(input: &str, err: peg::error::ParseError<peg::str::LineCol>) -> anyhow::Error {
+    let l = err.location.offset;
+    let found = input[l..].chars().take(20).collect::<String>();
+    anyhow::anyhow!("{err}\nFound: {found}...")
+}
+
 peg::parser! {
     grammar trace_parser() for str {
-        pub rule parse(blocks: &mut SourceBlocks) -> Vec<Trace>
-            = skip_to_module() module(blocks)* skip_to_trace() traces:trace_item()* {
-                traces
-            }
+        pub rule source(blocks: &mut SourceBlocks)
+            = skip_to_module() module(blocks)* [_]*
+
+        pub rule trace() -> Trace
+            = trace_item()
 
         rule module(blocks: &mut SourceBlocks)
             = m:mod_comment() keys:src_item(blocks)* {
@@ -39,7 +130,7 @@ peg::parser! {
             = "#" _ "mod" _
 
         rule src_item(blocks: &mut SourceBlocks) -> BlockKey
-            = call_conv()? ("pub" _)? ("export" / "proc") _ name:symbol() skip_to_eol() _
+            = call_conv()? ("pub" _)? ("export" / "proc") ("." / _) name:bare_symbol() skip_to_eol() _
                 ops:op(blocks)+
               end() {
                 blocks.insert(Block::new(name, ops))
@@ -50,6 +141,8 @@ peg::parser! {
 
         rule op(blocks: &mut SourceBlocks) -> Op
             = cond_block(blocks)
+            / loop_block(blocks)
+            / repeat_block(blocks)
             / basic_op()
 
         rule basic_op() -> Op
@@ -68,19 +161,41 @@ peg::parser! {
             }
 
         rule cond_block(blocks: &mut SourceBlocks) -> Op
-            = "if.true" _ tops:op(blocks)* "else" _ fops:op(blocks)* end() {
-                let tops_key = blocks.insert(Block::bare(tops));
-                let fops_key = blocks.insert(Block::bare(fops));
-                Op::Conditional(tops_key, fops_key)
+            = "if." on_true:cond_polarity() _ tops:op(blocks)* fops:else_arm(blocks)? end() {
+                let then_block = blocks.insert(Block::bare(tops));
+                let else_block = fops.map(|fops| blocks.insert(Block::bare(fops)));
+                Op::Conditional { on_true, then_block, else_block }
             }
 
+        rule cond_polarity() -> bool
+            = "true" { true }
+            / "false" { false }
+
+        rule else_arm(blocks: &mut SourceBlocks) -> Vec<Op>
+            = "else" _ ops:op(blocks)* {
+                ops
+            }
+
+        rule loop_block(blocks: &mut SourceBlocks) -> Op
+            = "while.true" _ ops:op(blocks)* end() {
+                Op::Loop(blocks.insert(Block::bare(ops)))
+            }
+
+        rule repeat_block(blocks: &mut SourceBlocks) -> Op
+            = "repeat." count:num() ops:op(blocks)* end() {
+                Op::Repeat(count as usize, blocks.insert(Block::bare(ops)))
+            }
+
+        // A keyword is only one when it isn't the start of a longer identifier.
         rule keyword()
-            = "export" / "proc" / "if" / "else" / "end"
+            = ("export" / "proc" / "if" / "else" / "while" / "repeat" / "end") !ident_char()
 
         rule trace_item() -> Trace
-            = func:trace_in() exe:trace_executed() stack:trace_stack() trace_locals()* skip_to_trace() {
-                let (_masm_op, op, cycle, total) = exe;
-                Trace { func, op, cycle, total, stack }
+            = func:trace_in() exe:trace_executed() stack:trace_stack() trace_locals()*
+              failure:failure_report()? skip_to_trace() {
+                let (vm_op, op, cycle, total) = exe;
+                let failure = failure.map(Box::new);
+                Trace { func, op, vm_op, cycle, total, stack, failure }
             }
 
         rule trace_in() -> String
@@ -95,8 +210,8 @@ peg::parser! {
             }
 
         rule trace_ops() -> (String, Op)
-            = "`" masm_op:$((!"`" [_])*)  "`" _ "of" _ "`" op:basic_op() "`" _ {
-                (masm_op.to_string(), op)
+            = "`" vm_op:$((!"`" [_])*)  "`" _ "of" _ "`" op:basic_op() "`" _ {
+                (vm_op.to_string(), op)
             }
 
         rule trace_stack() -> Vec<u64>
@@ -114,8 +229,60 @@ peg::parser! {
 
         rule trace_marker() = "[TRACE executor]" _
 
+        // The executor's report when execution fails, which follows the final trace event.
+        rule failure_report() -> FailureReport
+            = (!("Stack Trace:" / trace_marker()) [_])* "Stack Trace:" _
+              stack_trace:report_frame()+
+              last_instructions:report_instructions()?
+              last_instructions_any_frame:report_instructions()?
+              state:report_last_known_state()?
+              (!"program execution failed" [_])* "program execution failed at step" _ step:num()
+              "(cycle" _ cycle:num() ")" ":" [' ']* message:$(skip_to_eol()) {
+                let (frame_pointer, operand_stack) = state.unzip();
+                FailureReport {
+                    stack_trace,
+                    last_instructions: last_instructions.unwrap_or_default(),
+                    last_instructions_any_frame: last_instructions_any_frame.unwrap_or_default(),
+                    frame_pointer,
+                    operand_stack: operand_stack.unwrap_or_default(),
+                    step,
+                    cycle,
+                    message: message.trim_end().to_string(),
+                }
+            }
+
+        rule report_frame() -> String
+            = ("|->" / "`->") _ sym:bare_symbol() skip_to_eol() _ {
+                sym
+            }
+
+        // Those of the current frame, then those of any frame.
+        rule report_instructions() -> Vec<String>
+            = "Last" _ num() "Instructions" _ skip_in_paren() ":" _
+              instrs:report_instruction()* "`->" skip_to_eol() _ {
+                instrs
+            }
+
+        rule report_instruction() -> String
+            = "|" [' ']+ instr:$(skip_to_eol()) _ {
+                instr.trim_end().to_string()
+            }
+
+        rule report_last_known_state() -> (u64, Vec<u64>)
+            = "Last Known State" skip_to_eol() _
+              "|" _ "Frame Pointer:" _ fp:num() skip_to_eol() _
+              "|" _ "Operand Stack:" _ "[" _ stack:csn() "]" skip_to_eol() _ {
+                (fp, stack)
+            }
+
         rule symbol() -> String
-            = s:$(sym_char() (sym_char() / ['0'..='9'])*) _ {
+            = s:bare_symbol() _ {
+                s
+            }
+
+        // A symbol without any trailing whitespace, for when we need to stay on the same line.
+        rule bare_symbol() -> String
+            = s:$(sym_char() (sym_char() / ['0'..='9'])*) {
                 s.to_string()
             }
 
@@ -123,10 +290,13 @@ peg::parser! {
             = quiet!{['a'..='z' | 'A'..='Z' | '.' | '/' | '@' | '_' | '-' | ':' | '#' | '$']}
 
         rule ident() -> String
-            = !keyword() id:$(['a'..='z' | 'A'..='Z' | '0'..='9' | '_']+) _ {
+            = !keyword() id:$(ident_char()+) _ {
                 id.to_string()
             }
 
+        rule ident_char()
+            = ['a'..='z' | 'A'..='Z' | '0'..='9' | '_']
+
         rule csn() -> Vec<u64>
             = num() ** ("," _)
 
@@ -0,0 +1,141 @@
+use std::io::Write;
+
+use crate::replay::ReplayEvent;
+
+/// Attributes the VM cycles of a replay to the procedures on the call stack at the time.
+#[derive(Debug, Default)]
+pub struct Profiler {
+    call_stack: Vec<String>,
+
+    // The cycles spent in the innermost procedure of each distinct call stack.
+    stack_cycles: fxhash::FxHashMap<Vec<String>, u64>,
+    calls: fxhash::FxHashMap<String, u64>,
+}
+
+/// The cycles attributed to a single procedure.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProcProfile {
+    pub func: String,
+
+    /// Cycles spent in the procedure itself.
+    pub self_cycles: u64,
+
+    /// Cycles spent in the procedure and everything it called.
+    pub inclusive_cycles: u64,
+
+    pub calls: u64,
+}
+
+impl Profiler {
+    pub fn record(&mut self, event: &ReplayEvent) {
+        match event {
+            ReplayEvent::Entry { func } => {
+                self.call_stack = vec![func.clone()];
+                *self.calls.entry(func.clone()).or_default() += 1;
+            }
+
+            ReplayEvent::Enter { callee, .. } => {
+                self.call_stack.push(callee.clone());
+                *self.calls.entry(callee.clone()).or_default() += 1;
+            }
+
+            ReplayEvent::Return { .. } => {
+                self.call_stack.pop();
+            }
+
+            ReplayEvent::OpExecuted { cycles, .. } => self.add_cycles(None, *cycles),
+
+            ReplayEvent::SkippedCycles { callee, cycles, .. } => {
+                *self.calls.entry(callee.clone()).or_default() += 1;
+                self.add_cycles(Some(callee), *cycles);
+            }
+
+            _ => {}
+        }
+    }
+
+    fn add_cycles(&mut self, callee: Option<&String>, cycles: u64) {
+        let mut stack = self.call_stack.clone();
+        stack.extend(callee.cloned());
+        *self.stack_cycles.entry(stack).or_default() += cycles;
+    }
+
+    /// The total cycles attributed so far.
+    pub fn total_cycles(&self) -> u64 {
+        self.stack_cycles.values().sum()
+    }
+
+    /// The profile of each procedure, most expensive first.
+    pub fn report(&self) -> Vec<ProcProfile> {
+        let mut profiles = fxhash::FxHashMap::<&str, ProcProfile>::default();
+        for (stack, cycles) in &self.stack_cycles {
+            for (idx, func) in stack.iter().enumerate() {
+                let profile = profiles.entry(func).or_insert_with(|| ProcProfile {
+                    func: func.clone(),
+                    self_cycles: 0,
+                    inclusive_cycles: 0,
+                    calls: self.calls.get(func).copied().unwrap_or_default(),
+                });
+
+                // Recursive procedures only count their inclusive cycles once.
+                if !stack[..idx].contains(func) {
+                    profile.inclusive_cycles += cycles;
+                }
+                if idx == stack.len() - 1 {
+                    profile.self_cycles += cycles;
+                }
+            }
+        }
+
+        let mut report = profiles.into_values().collect::<Vec<_>>();
+        report.sort_by(|a, b| {
+            b.inclusive_cycles
+                .cmp(&a.inclusive_cycles)
+                .then_with(|| a.func.cmp(&b.func))
+        });
+        report
+    }
+
+    /// Print the report as a table.
+    pub fn write_report(&self, mut out: impl Write) -> anyhow::Result<()> {
+        writeln!(
+            out,
+            "{:>12} {:>12} {:>8}  procedure",
+            "inclusive", "self", "calls"
+        )?;
+        for ProcProfile {
+            func,
+            self_cycles,
+            inclusive_cycles,
+            calls,
+        } in self.report()
+        {
+            writeln!(
+                out,
+                "{inclusive_cycles:>12} {self_cycles:>12} {calls:>8}  {func}"
+            )?;
+        }
+        writeln!(out)?;
+        writeln!(out, "{:>12} total cycles", self.total_cycles())?;
+
+        Ok(())
+    }
+
+    /// Write the collapsed stacks, as consumed by `inferno` or `flamegraph.pl`.
+    pub fn write_folded(&self, mut out: impl Write) -> anyhow::Result<()> {
+        let mut stacks = self
+            .stack_cycles
+            .iter()
+            .map(|(stack, cycles)| (stack.join(";"), cycles))
+            .collect::<Vec<_>>();
+        stacks.sort();
+
+        for (stack, cycles) in stacks {
+            writeln!(out, "{stack} {cycles}")?;
+        }
+
+        Ok(())
+    }
+}
+
+// vim:fdl=3
@@ -0,0 +1,446 @@
+use std::io::Write;
+
+use crate::{
+    intrinsics::SimulatedCall,
+    masm::Op,
+    memory, render,
+    replay::{Branch, ReplayEvent},
+    semantics,
+    src_mgr::{BlockType, SourcePos},
+};
+
+// Distinct values standing in for the stack slots when working out how an op moves them about.
+// They're u32s, so the u32 ops accept them, and spaced so arithmetic on them won't make another.
+const SENTINEL_BASE: u64 = 0x4000_0000;
+const SENTINEL_STEP: u64 = 0x1_0001;
+
+// How far back through the producers an explanation goes.
+const MAX_EXPLAIN_DEPTH: usize = 16;
+
+/// An index into the producers recorded by a `Provenance`.
+pub type ProducerId = usize;
+
+/// What a producer did with its value.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProducerKind {
+    /// Computed it, from its inputs.
+    Op,
+
+    /// Loaded it from the felt address; the input is the store which wrote it, if known.
+    Load(u64),
+
+    /// Stored it to the felt address; the input produced the value stored.
+    Store(u64),
+}
+
+/// An op which produced a value, either on the stack or in memory.
+#[derive(Clone, Debug)]
+pub struct Producer {
+    pub func: String,
+    pub op: Op,
+    pub pos: Option<SourcePos>,
+
+    /// The index into the trace of the op's final cycle.
+    pub cycle: usize,
+    pub value: u64,
+    pub kind: ProducerKind,
+
+    /// The producers of the values the op consumed.
+    pub inputs: Vec<ProducerId>,
+}
+
+/// Tracks which op produced each value on the operand stack, following values as they're moved
+/// about by stack shuffles and through memory.
+#[derive(Debug, Default)]
+pub struct Provenance {
+    producers: Vec<Producer>,
+
+    // The producer of each stack slot, top first, and the values as last traced.  Values from
+    // before the replay started have no producer.
+    stack: Vec<Option<ProducerId>>,
+    values: Vec<u64>,
+
+    // The store which last wrote each felt address.
+    memory: fxhash::FxHashMap<u64, ProducerId>,
+
+    call_stack: Vec<String>,
+    cycle: usize,
+
+    // The loads or stores made by the last op, waiting for the address they accessed.
+    pending_loads: Vec<ProducerId>,
+    pending_stores: Vec<ProducerId>,
+
+    skipped: Option<SkippedCall>,
+}
+
+// The last skipped call, with the stack slots and values before it.
+#[derive(Clone, Debug)]
+struct SkippedCall {
+    op: Op,
+    pos: SourcePos,
+    stack: Vec<Option<ProducerId>>,
+    values: Vec<u64>,
+}
+
+impl Provenance {
+    pub fn record(&mut self, event: &ReplayEvent) {
+        match event {
+            ReplayEvent::Entry { func } => {
+                *self = Self {
+                    producers: std::mem::take(&mut self.producers),
+                    call_stack: vec![func.clone()],
+                    ..Self::default()
+                };
+            }
+            ReplayEvent::Enter { callee, .. } => self.call_stack.push(callee.clone()),
+            ReplayEvent::Return { .. } => {
+                self.call_stack.pop();
+            }
+
+            // Conditions are dropped from the stack without appearing in the trace.
+            ReplayEvent::BranchTaken {
+                branch: Branch::If { .. } | Branch::While,
+                ..
+            }
+            | ReplayEvent::Iterate {
+                block: BlockType::LoopBody(_),
+                ..
+            }
+            | ReplayEvent::BlockEnd {
+                block: BlockType::LoopBody(_),
+                ..
+            } if !self.stack.is_empty() => {
+                self.stack.remove(0);
+                self.values.remove(0);
+            }
+
+            ReplayEvent::OpExecuted {
+                func,
+                op,
+                pos,
+                cycle,
+                stack,
+                ..
+            } => {
+                self.cycle = *cycle;
+                self.execute(func, op, Some(*pos), stack);
+            }
+
+            ReplayEvent::Skipped { op, pos, .. } => {
+                self.skipped = Some(SkippedCall {
+                    op: op.clone(),
+                    pos: *pos,
+                    stack: self.stack.clone(),
+                    values: self.values.clone(),
+                });
+            }
+            ReplayEvent::SkippedCycles { cycle, stack, .. } => {
+                if let Some(SkippedCall { op, pos, .. }) = self.skipped.clone() {
+                    self.cycle = *cycle;
+                    let func = self.call_stack.last().cloned().unwrap_or_default();
+                    self.shift(&func, &op, Some(pos), stack);
+                }
+            }
+            ReplayEvent::Simulated { call, .. } => self.simulated_io(call),
+
+            ReplayEvent::MemoryAccess { addr, .. } => {
+                for (offs, load) in std::mem::take(&mut self.pending_loads)
+                    .into_iter()
+                    .enumerate()
+                {
+                    let addr = addr + offs as u64;
+                    self.producers[load].kind = ProducerKind::Load(addr);
+                    self.producers[load].inputs =
+                        self.memory.get(&addr).copied().into_iter().collect();
+                }
+                for (offs, store) in std::mem::take(&mut self.pending_stores)
+                    .into_iter()
+                    .enumerate()
+                {
+                    let addr = addr + offs as u64;
+                    self.producers[store].kind = ProducerKind::Store(addr);
+                    self.memory.insert(addr, store);
+                }
+            }
+
+            _ => {}
+        }
+    }
+
+    /// The index into the trace of the last cycle recorded.
+    pub fn cycle(&self) -> usize {
+        self.cycle
+    }
+
+    /// The producer of the value in the stack slot, if known.
+    pub fn producer_of(&self, slot: usize) -> Option<ProducerId> {
+        self.stack.get(slot).copied().flatten()
+    }
+
+    pub fn producer(&self, id: ProducerId) -> &Producer {
+        &self.producers[id]
+    }
+
+    /// The current value in the stack slot, as traced.
+    pub fn value(&self, slot: usize) -> Option<u64> {
+        self.values.get(slot).copied()
+    }
+
+    fn execute(&mut self, func: &str, op: &Op, pos: Option<SourcePos>, stack: &[u64]) {
+        self.pending_loads.clear();
+        self.pending_stores.clear();
+        if self.values.is_empty() {
+            self.values = vec![0; semantics::STACK_DEPTH];
+            self.stack = vec![None; semantics::STACK_DEPTH];
+        }
+
+        // Memory ops are followed by the address they accessed.  Those with an immediate address
+        // don't take it from the stack.
+        let opcode = op.opcode().unwrap_or_default();
+        let addr_arg = usize::from(opcode.starts_with("mem_") && op.arg().is_none());
+        let (consumed, loaded, stored) = match opcode {
+            "mem_load" | "loc_load" => (addr_arg, 1, 0..0),
+            "mem_loadw" | "loc_loadw" => (addr_arg + 4, 4, 0..0),
+            "mem_store" | "loc_store" => (addr_arg + 1, 0, addr_arg..addr_arg + 1),
+            "mem_storew" | "loc_storew" => (addr_arg, 0, addr_arg..addr_arg + 4),
+            _ => {
+                if !self.move_slots(func, op, pos, stack) {
+                    self.shift(func, op, pos, stack);
+                }
+                return;
+            }
+        };
+
+        for slot in stored {
+            let store = self.add_producer(
+                func,
+                op,
+                pos,
+                self.value(slot).unwrap_or_default(),
+                self.producer_of(slot).into_iter().collect(),
+            );
+            self.pending_stores.push(store);
+        }
+        // Memory holds words with the top of the stack last.
+        self.pending_stores.reverse();
+
+        let loads = (0..loaded)
+            .map(|slot| {
+                let value = stack.get(slot).copied().unwrap_or_default();
+                self.add_producer(func, op, pos, value, Vec::new())
+            })
+            .collect::<Vec<_>>();
+        self.pending_loads = loads.iter().rev().copied().collect();
+
+        let mut slots = loads.into_iter().map(Some).collect::<Vec<_>>();
+        slots.extend(self.stack.iter().skip(consumed));
+        self.update(slots, stack);
+    }
+
+    // Work out how an op moved the stack slots, by evaluating it on a stack of distinct values.
+    // Any other values it leaves were produced by it, from those it consumed.  Returns false if the
+    // op can't be evaluated.
+    fn move_slots(&mut self, func: &str, op: &Op, pos: Option<SourcePos>, stack: &[u64]) -> bool {
+        let sentinels = (0..self.values.len() as u64)
+            .map(|idx| SENTINEL_BASE + idx * SENTINEL_STEP)
+            .collect::<Vec<_>>();
+        let Some(moved) = semantics::eval(op, &sentinels) else {
+            return false;
+        };
+
+        let slot_of = |val: u64| {
+            let offs = val.checked_sub(SENTINEL_BASE)?;
+            let slot = (offs / SENTINEL_STEP) as usize;
+            (offs % SENTINEL_STEP == 0 && slot < sentinels.len()).then_some(slot)
+        };
+
+        let inputs = (0..sentinels.len())
+            .filter(|slot| !moved.contains(&sentinels[*slot]))
+            .filter_map(|slot| self.producer_of(slot))
+            .collect::<Vec<_>>();
+
+        // Zeros below the last moved slot are padding from the overflow table.
+        let last_moved = moved.iter().rposition(|val| slot_of(*val).is_some());
+        let slots = moved
+            .iter()
+            .enumerate()
+            .map(|(idx, val)| match slot_of(*val) {
+                Some(slot) => self.stack[slot],
+                None if *val == 0 && last_moved.is_some_and(|last| idx > last) => None,
+                None => {
+                    let value = stack.get(idx).copied().unwrap_or(*val);
+                    Some(self.add_producer(func, op, pos, value, inputs.clone()))
+                }
+            })
+            .collect();
+        self.update(slots, stack);
+
+        true
+    }
+
+    // For ops which can't be evaluated, assume they consumed and produced as few values as
+    // would leave the rest of the stack as it was.  The trace may not show all of the stack, so
+    // only the slots shown both before and after are compared.
+    fn shift(&mut self, func: &str, op: &Op, pos: Option<SourcePos>, stack: &[u64]) {
+        let (consumed, produced) = (0..=self.values.len() + stack.len())
+            .flat_map(|total| (0..=total).map(move |consumed| (consumed, total - consumed)))
+            .find(|&(consumed, produced)| {
+                consumed <= self.values.len()
+                    && produced <= stack.len()
+                    && self.values[consumed..]
+                        .iter()
+                        .zip(&stack[produced..])
+                        .all(|(before, after)| before == after)
+            })
+            .unwrap_or((self.values.len(), stack.len()));
+
+        let inputs = (0..consumed)
+            .filter_map(|slot| self.producer_of(slot))
+            .collect::<Vec<_>>();
+        let mut slots = (0..produced)
+            .map(|slot| Some(self.add_producer(func, op, pos, stack[slot], inputs.clone())))
+            .collect::<Vec<_>>();
+        slots.extend(self.stack.iter().skip(consumed));
+        self.update(slots, stack);
+    }
+
+    // Follow the values stored or loaded by the memory intrinsics, which take a byte address.
+    fn simulated_io(&mut self, call: &SimulatedCall) {
+        let Some(skipped) = self.skipped.clone() else {
+            return;
+        };
+        let Some(&(_, byte_addr)) = call.args.iter().find(|(name, _)| *name == "addr") else {
+            return;
+        };
+        let addr = byte_addr / memory::BYTES_PER_FELT;
+
+        match call.name.as_str() {
+            "load_felt" | "load_sw" | "load_dw" => {
+                for (slot, addr) in (0..call.results.len()).zip(addr..) {
+                    let Some(load) = self.producer_of(slot) else {
+                        continue;
+                    };
+                    // Only values produced by the call itself were loaded.
+                    if self.producers[load].cycle == self.cycle {
+                        self.producers[load].kind = ProducerKind::Load(addr);
+                        self.producers[load].inputs =
+                            self.memory.get(&addr).copied().into_iter().collect();
+                    }
+                }
+            }
+            "store_felt" | "store_sw" | "store_dw" => {
+                // [addr, offset, value, ...], or [addr, offset, hi, lo, ...] for double words.
+                let num_stored = if call.name == "store_dw" { 2 } else { 1 };
+                let func = self.call_stack.last().cloned().unwrap_or_default();
+                for (slot, addr) in (2..2 + num_stored).zip(addr..) {
+                    let value = skipped.values.get(slot).copied().unwrap_or_default();
+                    let inputs = skipped
+                        .stack
+                        .get(slot)
+                        .copied()
+                        .flatten()
+                        .into_iter()
+                        .collect();
+                    let store =
+                        self.add_producer(&func, &skipped.op, Some(skipped.pos), value, inputs);
+                    self.producers[store].kind = ProducerKind::Store(addr);
+                    self.memory.insert(addr, store);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn add_producer(
+        &mut self,
+        func: &str,
+        op: &Op,
+        pos: Option<SourcePos>,
+        value: u64,
+        inputs: Vec<ProducerId>,
+    ) -> ProducerId {
+        self.producers.push(Producer {
+            func: func.to_string(),
+            op: op.clone(),
+            pos,
+            cycle: self.cycle,
+            value,
+            kind: ProducerKind::Op,
+            inputs,
+        });
+        self.producers.len() - 1
+    }
+
+    // The trace may show more or fewer of the stack than we were tracking.
+    fn update(&mut self, mut slots: Vec<Option<ProducerId>>, stack: &[u64]) {
+        slots.resize(stack.len(), None);
+        self.stack = slots;
+        self.values = stack.to_vec();
+    }
+
+    /// Write the chain of producers of the value in the stack slot.
+    pub fn write_explanation(&self, slot: usize, mut out: impl Write) -> anyhow::Result<()> {
+        let Some(value) = self.value(slot) else {
+            writeln!(out, "There is no stack slot {slot}.")?;
+            return Ok(());
+        };
+        writeln!(
+            out,
+            "Slot {slot} holds {} after cycle {}.",
+            render::format_felt(value),
+            self.cycle
+        )?;
+
+        match self.producer_of(slot) {
+            Some(id) => self.write_producer(&mut out, id, 1, &mut Vec::new())?,
+            None => writeln!(out, "    It was on the stack before the replay started.")?,
+        }
+        Ok(())
+    }
+
+    fn write_producer(
+        &self,
+        out: &mut impl Write,
+        id: ProducerId,
+        depth: usize,
+        shown: &mut Vec<ProducerId>,
+    ) -> anyhow::Result<()> {
+        let indent = "    ".repeat(depth);
+        let producer = &self.producers[id];
+        let value = render::format_felt(producer.value);
+        let action = match producer.kind {
+            ProducerKind::Op => format!("{value} from"),
+            ProducerKind::Load(addr) => format!("{value} loaded from addr {addr:x} by"),
+            ProducerKind::Store(addr) => format!("{value} stored to addr {addr:x} by"),
+        };
+        let pc = producer
+            .pos
+            .map(|pos| format!("pc {}, ", pos.pc))
+            .unwrap_or_default();
+        write!(
+            out,
+            "{indent}{action} {} in {} ({pc}cycle {})",
+            producer.op, producer.func, producer.cycle
+        )?;
+
+        if shown.contains(&id) {
+            writeln!(out, ", as above")?;
+            return Ok(());
+        }
+        writeln!(out)?;
+        shown.push(id);
+
+        if depth >= MAX_EXPLAIN_DEPTH {
+            if !producer.inputs.is_empty() {
+                writeln!(out, "{indent}    ...")?;
+            }
+            return Ok(());
+        }
+        for input in &producer.inputs {
+            self.write_producer(out, *input, depth + 1, shown)?;
+        }
+        Ok(())
+    }
+}
+
+// vim:fdl=3
@@ -0,0 +1,647 @@
+use std::io::Write;
+
+use crate::{
+    intrinsics, masm, memory,
+    replay::{Branch, ReplayEvent},
+    src_mgr,
+};
+
+const SPACES: &str = "                                                                                                    ";
+
+fn spaces(count: usize) -> &'static str {
+    &SPACES[0..count]
+}
+
+fn indent(depth: usize) -> &'static str {
+    spaces(depth * src_mgr::INDENT_AMOUNT)
+}
+
+/// A felt as shown on the stack; small values in decimal and larger ones in hex.
+pub(crate) fn format_felt(val: u64) -> String {
+    if val < 256 {
+        val.to_string()
+    } else {
+        format!("{val:x}h")
+    }
+}
+
+/// The stack as shown after each op, with the trailing zeros elided.
+pub(crate) fn format_stack(stack: &[u64]) -> String {
+    // Find the index to the last non-zero value first.
+    let nz_idx = stack
+        .iter()
+        .rev()
+        .position(|item| *item != 0)
+        .unwrap_or(stack.len());
+    let num_items_to_print = (stack.len() + 2 - nz_idx).min(stack.len());
+
+    let mut out_str = "[".to_string();
+    for el in stack.iter().take(num_items_to_print) {
+        out_str += " ";
+        out_str += &format_felt(*el);
+    }
+    if num_items_to_print < stack.len() {
+        out_str += " ...";
+    }
+    out_str + " ]"
+}
+
+/// The known felts of a memory word in hex, with `?`s for those unknown.
+pub(crate) fn format_word(words: &[Option<u64>; 4]) -> String {
+    words
+        .iter()
+        .map(|word| match word {
+            Some(mem_val) => format!("{mem_val:0>16x}"),
+            None => "????????????????".to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Something which can present replay events to the user.
+pub trait Renderer {
+    fn render(&mut self, event: &ReplayEvent) -> anyhow::Result<()>;
+}
+
+/// Renders a replay as an indented listing of the source with the stack after each op.
+pub struct TextRenderer<W: Write> {
+    out: W,
+    entered: bool,
+}
+
+impl<W: Write> TextRenderer<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            out,
+            entered: false,
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+impl<W: Write> Renderer for TextRenderer<W> {
+    fn render(&mut self, event: &ReplayEvent) -> anyhow::Result<()> {
+        let out = &mut self.out;
+
+        match event {
+            ReplayEvent::Entry { func } => {
+                // Repeated entries are separated from the prior one.
+                if self.entered {
+                    writeln!(out)?;
+                }
+                self.entered = true;
+
+                writeln!(out, "ENTRY AT {func}")?;
+            }
+
+            ReplayEvent::OpExecuted {
+                func,
+                op,
+                stack,
+                depth,
+                ..
+            } => print_op(out, op, func, Some(stack), *depth)?,
+
+            ReplayEvent::Enter {
+                op, callee, depth, ..
+            } => {
+                print_op(out, op, callee, None, *depth)?;
+
+                writeln!(out)?;
+                writeln!(out, "ENTERING {callee} {{{{{{")?;
+            }
+
+            ReplayEvent::Return { func } => {
+                writeln!(out, "RETURN TO {func} }}}}}}")?;
+                writeln!(out)?;
+            }
+
+            ReplayEvent::BranchTaken {
+                branch,
+                taken,
+                depth,
+            } => {
+                let header = match branch {
+                    Branch::If { on_true, .. } => format!("if.{on_true}"),
+                    Branch::While if *taken => "while.true [iter 1]".to_string(),
+                    Branch::While => "while.true".to_string(),
+                    Branch::Repeat(count) if *taken => format!("repeat.{count} [iter 1/{count}]"),
+                    Branch::Repeat(count) => format!("repeat.{count}"),
+                };
+                writeln!(out, "{}{header}", indent(*depth))?;
+
+                if !taken {
+                    writeln!(out, "{}(SKIPPING)", indent(depth + 1))?;
+
+                    if matches!(branch, Branch::If { has_else: true, .. }) {
+                        writeln!(out, "{}else", indent(*depth))?;
+                    } else {
+                        writeln!(out, "{}end", indent(*depth))?;
+                    }
+                }
+            }
+
+            ReplayEvent::Iterate { block, depth } => match block {
+                src_mgr::BlockType::LoopBody(iter) => {
+                    writeln!(out, "{}while.true [iter {iter}]", indent(*depth))?;
+                }
+                src_mgr::BlockType::RepeatBody(iter, count) => {
+                    writeln!(
+                        out,
+                        "{}repeat.{count} [iter {iter}/{count}]",
+                        indent(*depth)
+                    )?;
+                }
+                _ => unreachable!("Only loop bodies may iterate."),
+            },
+
+            ReplayEvent::BlockEnd { block, depth } => {
+                if let src_mgr::BlockType::ThenBlock { has_else: true } = block {
+                    writeln!(out, "{}else", indent(*depth))?;
+                    writeln!(out, "{}(SKIPPING)", indent(depth + 1))?;
+                }
+                writeln!(out, "{}end", indent(*depth))?;
+            }
+
+            ReplayEvent::Skipped {
+                op, callee, depth, ..
+            } => {
+                print_op(out, op, callee, None, *depth)?;
+                if !intrinsics::is_simulated(callee) {
+                    writeln!(out, "{}(SKIPPING)", indent(depth + 1))?;
+                }
+            }
+
+            ReplayEvent::Hidden {
+                op,
+                cycles,
+                stack_before,
+                stack_after,
+                depth,
+                ..
+            } => {
+                writeln!(
+                    out,
+                    "{}{op} -> returned after {cycles} cycles, stack {} -> {}",
+                    indent(*depth),
+                    format_stack(stack_before),
+                    format_stack(stack_after)
+                )?;
+            }
+
+            ReplayEvent::Simulated { call, depth } => {
+                writeln!(out, "{}{call}", indent(depth + 1))?;
+            }
+
+            // The text listing doesn't show timings.
+            ReplayEvent::SkippedCycles { .. } => {}
+
+            ReplayEvent::MemoryAccess {
+                base_addr, words, ..
+            } => {
+                // The felt address followed by the byte address it's at, for the intrinsics.
+                let base_byte_addr = memory::native_ptr_byte_addr(*base_addr, 0);
+                writeln!(out)?;
+                writeln!(
+                    out,
+                    "| {base_addr:0>8x} ({base_byte_addr:0>8x}):  {} |",
+                    format_word(words)
+                )?;
+                writeln!(out)?;
+            }
+
+            ReplayEvent::StackMismatch {
+                func,
+                op,
+                expected,
+                traced,
+            } => {
+                writeln!(out, "WARNING: stack mismatch after {op} in {func}:")?;
+                writeln!(out, "  Expected {}", format_stack(expected))?;
+                writeln!(out, "  Traced   {}", format_stack(traced))?;
+            }
+
+            ReplayEvent::MemoryMismatch {
+                addr,
+                loaded,
+                stored,
+            } => {
+                writeln!(out, "WARNING: memory mismatch at addr {addr:x}:")?;
+                writeln!(out, "  Expecting {loaded:x}, found {stored:x}")?;
+            }
+
+            ReplayEvent::MemoryWatch {
+                op,
+                addr,
+                cycle,
+                func,
+                old,
+                new,
+            } => {
+                let op = match op {
+                    memory::MemoryOp::Load => "load from",
+                    memory::MemoryOp::Store => "store to",
+                };
+                writeln!(out, "WATCH: {op} addr {addr:x} at cycle {cycle} in {func}:")?;
+                let old = old.map_or("????????????????".to_string(), |old| format!("{old:0>16x}"));
+                writeln!(out, "  {old} -> {new:0>16x}")?;
+            }
+
+            ReplayEvent::UninitRead {
+                addr, cycle, func, ..
+            } => {
+                writeln!(
+                    out,
+                    "WARNING: load from unwritten addr {addr:x} at cycle {cycle} in {func}"
+                )?;
+            }
+
+            ReplayEvent::Mismatch {
+                src_func,
+                src_op,
+                trace_func,
+                trace_op,
+            } => {
+                writeln!(out)?;
+                writeln!(out, "src func: {src_func}")?;
+                writeln!(out, "  src op {src_op:?}")?;
+                writeln!(out, "trace func: {trace_func}")?;
+                writeln!(out, "  op {trace_op:?}")?;
+            }
+
+            ReplayEvent::Divergence {
+                src_ops,
+                trace_ops,
+                depth,
+                ..
+            } => {
+                // Show the divergent region like a diff, from the source to the trace.
+                writeln!(out, "{}DIVERGED FROM TRACE {{{{{{", indent(*depth))?;
+                for op in src_ops {
+                    writeln!(out, "{}- {op}", indent(*depth))?;
+                }
+                for op in trace_ops {
+                    writeln!(out, "{}+ {op}", indent(*depth))?;
+                }
+                writeln!(out, "{}}}}}}} RESYNCED", indent(*depth))?;
+            }
+
+            ReplayEvent::Failure {
+                op,
+                depth,
+                report,
+                call_stack,
+                call_stack_matches,
+                ..
+            } => {
+                writeln!(out, "{}{op}  <<< FAILED HERE", indent(*depth))?;
+                writeln!(out)?;
+                writeln!(
+                    out,
+                    "EXECUTION FAILED AT STEP {} (cycle {}): {}",
+                    report.step, report.cycle, report.message
+                )?;
+                if let Some(error_code) = report.error_code() {
+                    writeln!(out, "ERROR CODE: {error_code}")?;
+                }
+                writeln!(out, "CALL STACK:")?;
+                for func in call_stack {
+                    writeln!(out, "  {func}")?;
+                }
+                if !call_stack_matches {
+                    writeln!(out, "WARNING: the reported stack trace differs:")?;
+                    for func in &report.stack_trace {
+                        writeln!(out, "  {func}")?;
+                    }
+                }
+            }
+
+            ReplayEvent::EntryReturned => writeln!(out, "RETURNED FROM ENTRY POINT")?,
+
+            ReplayEvent::FunctionNotFound { .. } => writeln!(out, "FUNCTION NOT FOUND")?,
+
+            ReplayEvent::EndOfTrace => {
+                writeln!(out)?;
+                writeln!(out, "END OF TRACE")?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Renders a replay as newline delimited JSON, one object per event.  A `memory` object is the
+/// access made by the `op`, `skipped_cycles` or `simulated` object on the line before it.
+pub struct JsonRenderer<W: Write> {
+    out: W,
+}
+
+impl<W: Write> JsonRenderer<W> {
+    pub fn new(out: W) -> Self {
+        Self { out }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+impl<W: Write> Renderer for JsonRenderer<W> {
+    fn render(&mut self, event: &ReplayEvent) -> anyhow::Result<()> {
+        use serde_json::json;
+
+        let obj = match event {
+            ReplayEvent::Entry { func } => json!({ "event": "entry", "func": func }),
+
+            ReplayEvent::OpExecuted {
+                func,
+                op,
+                cycle,
+                cycles,
+                stack,
+                depth,
+                ..
+            } => json!({
+                "event": "op",
+                "func": func,
+                "opcode": op.opcode(),
+                "arg": op.arg(),
+                "cycle": cycle,
+                "cycles": cycles,
+                "stack": stack,
+                "depth": depth,
+            }),
+
+            ReplayEvent::Enter {
+                op, callee, depth, ..
+            } => json!({
+                "event": "enter",
+                "opcode": op.opcode(),
+                "callee": callee,
+                "depth": depth,
+            }),
+
+            ReplayEvent::Return { func } => json!({ "event": "return", "func": func }),
+
+            ReplayEvent::BranchTaken {
+                branch,
+                taken,
+                depth,
+            } => {
+                let branch = match branch {
+                    Branch::If { on_true, .. } => format!("if.{on_true}"),
+                    Branch::While => "while.true".to_string(),
+                    Branch::Repeat(count) => format!("repeat.{count}"),
+                };
+                json!({ "event": "branch", "branch": branch, "taken": taken, "depth": depth })
+            }
+
+            ReplayEvent::Iterate { block, depth } => match block {
+                src_mgr::BlockType::LoopBody(iter) => json!({
+                    "event": "iterate",
+                    "branch": "while.true",
+                    "iter": iter,
+                    "depth": depth,
+                }),
+                src_mgr::BlockType::RepeatBody(iter, count) => json!({
+                    "event": "iterate",
+                    "branch": format!("repeat.{count}"),
+                    "iter": iter,
+                    "count": count,
+                    "depth": depth,
+                }),
+                _ => unreachable!("Only loop bodies may iterate."),
+            },
+
+            ReplayEvent::BlockEnd { depth, .. } => json!({ "event": "end", "depth": depth }),
+
+            ReplayEvent::Skipped {
+                op, callee, depth, ..
+            } => json!({
+                "event": "skipped",
+                "opcode": op.opcode(),
+                "callee": callee,
+                "depth": depth,
+            }),
+
+            ReplayEvent::SkippedCycles {
+                callee,
+                cycles,
+                cycle,
+                stack,
+            } => json!({
+                "event": "skipped_cycles",
+                "callee": callee,
+                "cycles": cycles,
+                "cycle": cycle,
+                "stack": stack,
+            }),
+
+            ReplayEvent::Hidden {
+                op,
+                callee,
+                cycles,
+                stack_before,
+                stack_after,
+                depth,
+            } => json!({
+                "event": "hidden",
+                "opcode": op.opcode(),
+                "callee": callee,
+                "cycles": cycles,
+                "stack_before": stack_before,
+                "stack_after": stack_after,
+                "depth": depth,
+            }),
+
+            ReplayEvent::Simulated { call, depth } => json!({
+                "event": "simulated",
+                "name": call.name,
+                "args": call.args.iter().map(|(name, val)| (name.to_string(), json!(val))).collect::<serde_json::Map<_, _>>(),
+                "results": call.results,
+                "depth": depth,
+            }),
+
+            ReplayEvent::MemoryAccess {
+                addr,
+                byte_addr,
+                base_addr,
+                words,
+            } => json!({
+                "event": "memory",
+                "addr": addr,
+                "byte_addr": byte_addr,
+                "base_addr": base_addr,
+                "words": words,
+            }),
+
+            ReplayEvent::StackMismatch {
+                func,
+                op,
+                expected,
+                traced,
+            } => json!({
+                "event": "stack_mismatch",
+                "func": func,
+                "opcode": op.opcode(),
+                "arg": op.arg(),
+                "expected": expected,
+                "traced": traced,
+            }),
+
+            ReplayEvent::MemoryMismatch {
+                addr,
+                loaded,
+                stored,
+            } => json!({
+                "event": "memory_mismatch",
+                "addr": addr,
+                "loaded": loaded,
+                "stored": stored,
+            }),
+
+            ReplayEvent::MemoryWatch {
+                op,
+                addr,
+                cycle,
+                func,
+                old,
+                new,
+            } => json!({
+                "event": "memory_watch",
+                "op": match op {
+                    memory::MemoryOp::Load => "load",
+                    memory::MemoryOp::Store => "store",
+                },
+                "addr": addr,
+                "cycle": cycle,
+                "func": func,
+                "old": old,
+                "new": new,
+            }),
+
+            ReplayEvent::UninitRead {
+                addr,
+                cycle,
+                func,
+                loaded,
+            } => json!({
+                "event": "uninit_read",
+                "addr": addr,
+                "cycle": cycle,
+                "func": func,
+                "loaded": loaded,
+            }),
+
+            ReplayEvent::Mismatch {
+                src_func,
+                src_op,
+                trace_func,
+                trace_op,
+            } => json!({
+                "event": "mismatch",
+                "src_func": src_func,
+                "src_op": src_op.to_string(),
+                "trace_func": trace_func,
+                "trace_op": trace_op.to_string(),
+            }),
+
+            ReplayEvent::Divergence {
+                func,
+                src_ops,
+                trace_ops,
+                depth,
+            } => json!({
+                "event": "divergence",
+                "func": func,
+                "src_ops": src_ops.iter().map(ToString::to_string).collect::<Vec<_>>(),
+                "trace_ops": trace_ops.iter().map(ToString::to_string).collect::<Vec<_>>(),
+                "depth": depth,
+            }),
+
+            ReplayEvent::Failure {
+                func,
+                op,
+                depth,
+                report,
+                call_stack,
+                call_stack_matches,
+            } => json!({
+                "event": "failure",
+                "func": func,
+                "op": op.to_string(),
+                "depth": depth,
+                "step": report.step,
+                "cycle": report.cycle,
+                "message": report.message,
+                "error_code": report.error_code(),
+                "stack_trace": report.stack_trace,
+                "call_stack": call_stack,
+                "call_stack_matches": call_stack_matches,
+                "last_instructions": report.last_instructions,
+                "frame_pointer": report.frame_pointer,
+                "operand_stack": report.operand_stack,
+            }),
+
+            ReplayEvent::EntryReturned => json!({ "event": "entry_returned" }),
+
+            ReplayEvent::FunctionNotFound { func } => {
+                json!({ "event": "function_not_found", "func": func })
+            }
+
+            ReplayEvent::EndOfTrace => json!({ "event": "end_of_trace" }),
+        };
+
+        serde_json::to_writer(&mut self.out, &obj)?;
+        writeln!(self.out)?;
+
+        Ok(())
+    }
+}
+
+fn print_op(
+    out: &mut impl Write,
+    op: &masm::Op,
+    func: &str,
+    stack: Option<&[u64]>,
+    depth: usize,
+) -> anyhow::Result<()> {
+    use std::fmt::Write;
+
+    const STACK_INDENT_OFFS: usize = 40;
+
+    let masm::Op::Op { opcode, arg } = op else {
+        unreachable!("Unexpected non-regular op in {func} ({op:?})",);
+    };
+
+    let mut out_str = String::default();
+
+    // Print opcode.
+    write!(out_str, "{}{opcode}", indent(depth))?;
+    if let Some(arg) = arg {
+        write!(out_str, ".{arg}")?;
+    }
+
+    if let Some(stack) = stack {
+        // Pad out to the stack.
+        let stack_pad = if out_str.len() >= STACK_INDENT_OFFS {
+            // Nah, put the stack on the next line.
+            writeln!(out, "{out_str}")?;
+            out_str.clear();
+
+            STACK_INDENT_OFFS
+        } else {
+            STACK_INDENT_OFFS - out_str.len()
+        };
+        write!(out_str, "{}{}", spaces(stack_pad), format_stack(stack))?;
+    }
+
+    writeln!(out, "{out_str}")?;
+
+    Ok(())
+}
+
+// vim:fdl=3
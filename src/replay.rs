@@ -0,0 +1,1041 @@
+use std::collections::VecDeque;
+
+use crate::{
+    coverage, demangle, failure, intrinsics, masm, memory, resync, semantics, src_mgr, trace,
+};
+
+#[derive(Clone, Debug, Default)]
+pub struct ReplayOptions {
+    /// Entry function symbol.  Fuzzy matched against the end of the source function names.
+    pub entry_func: Option<String>,
+
+    /// Try again when returning from the entry point.
+    pub repeat: bool,
+
+    /// Show the symbols found in the trace without demangling them.  They're still demangled to
+    /// match against the source.
+    pub raw_symbols: bool,
+
+    /// Fail at the first mismatch between the source and trace rather than resynchronising.  Even
+    /// when not strict, mismatches are only resynchronised within the straight line source up to
+    /// the end of the block or the next call.
+    pub strict: bool,
+
+    /// Check the stack after each op against that expected from the MASM semantics.
+    pub check_semantics: bool,
+
+    /// Report each load and store of the felts in this range of addresses.
+    pub watch: Option<std::ops::Range<u64>>,
+
+    /// Report each load of a felt which hasn't been stored to.
+    pub check_uninit: bool,
+}
+
+/// The control flow constructs which may be entered or skipped over.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Branch {
+    If { on_true: bool, has_else: bool },
+    While,
+    Repeat(usize),
+}
+
+/// Everything of note which happens during a replay, in order.  The `depth` of an event is the
+/// nesting level of the source it refers to, within the current procedure.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ReplayEvent {
+    /// Replay has started, or restarted, at the entry function.
+    Entry { func: String },
+
+    /// A source op was matched against the trace.  `cycle` is the index into the trace of the op's
+    /// final VM cycle and `cycles` is how many VM cycles it took.
+    OpExecuted {
+        func: String,
+        op: masm::Op,
+        pos: src_mgr::SourcePos,
+        cycle: usize,
+        cycles: u64,
+        stack: Vec<u64>,
+        depth: usize,
+    },
+
+    /// An `exec` or `call` into a procedure from the source.
+    Enter {
+        op: masm::Op,
+        pos: src_mgr::SourcePos,
+        callee: String,
+        depth: usize,
+    },
+
+    /// Returned from a procedure back to `func`.
+    Return { func: String },
+
+    /// Arrived at a control flow construct; `taken` is whether its (first) block was entered.
+    BranchTaken {
+        branch: Branch,
+        taken: bool,
+        depth: usize,
+    },
+
+    /// Went around a loop again.  The block is either a `LoopBody` or `RepeatBody`.
+    Iterate {
+        block: src_mgr::BlockType,
+        depth: usize,
+    },
+
+    /// Reached the end of a control flow block.
+    BlockEnd {
+        block: src_mgr::BlockType,
+        depth: usize,
+    },
+
+    /// An `exec` or `call` to a procedure which isn't traced or isn't in the source.
+    Skipped {
+        op: masm::Op,
+        pos: src_mgr::SourcePos,
+        callee: String,
+        depth: usize,
+    },
+
+    /// A skipped procedure has returned, having taken `cycles` VM cycles.  `cycle` is the index
+    /// into the trace of its final cycle and `stack` is the stack it returned.
+    SkippedCycles {
+        callee: String,
+        cycles: u64,
+        cycle: usize,
+        stack: Vec<u64>,
+    },
+
+    /// A call to a procedure hidden from the output, which took `cycles` VM cycles and left
+    /// `stack_after`.  Only produced by a `filter::Filter`.
+    Hidden {
+        op: masm::Op,
+        callee: String,
+        cycles: u64,
+        stack_before: Vec<u64>,
+        stack_after: Vec<u64>,
+        depth: usize,
+    },
+
+    /// A skipped procedure which has a built-in model has returned.
+    Simulated {
+        call: intrinsics::SimulatedCall,
+        depth: usize,
+    },
+
+    /// Memory was read or written, or may have been by an intrinsic.  `addr` is the felt address
+    /// and `byte_addr` the byte address it was accessed by.  `words` are the known values of the
+    /// 4 felts from `base_addr`.  It directly follows the `OpExecuted` of the op which accessed
+    /// it, or the `SkippedCycles` or `Simulated` of the intrinsic.
+    MemoryAccess {
+        addr: u64,
+        byte_addr: u64,
+        base_addr: u64,
+        words: [Option<u64>; 4],
+    },
+
+    /// The stack after an op differs from that expected by the op's semantics, given the stack
+    /// before it.
+    StackMismatch {
+        func: String,
+        op: masm::Op,
+        expected: Vec<u64>,
+        traced: Vec<u64>,
+    },
+
+    /// A load found a value different to that which we believe was stored.
+    MemoryMismatch { addr: u64, loaded: u64, stored: u64 },
+
+    /// A watched felt was loaded or stored by `func`.  `old` is the value we believed was there
+    /// and `new` the value loaded or stored.
+    MemoryWatch {
+        op: memory::MemoryOp,
+        addr: u64,
+        cycle: usize,
+        func: String,
+        old: Option<u64>,
+        new: u64,
+    },
+
+    /// A felt was loaded by `func` before anything was stored to it.
+    UninitRead {
+        addr: u64,
+        cycle: usize,
+        func: String,
+        loaded: u64,
+    },
+
+    /// The source and trace disagree and can't be resynchronised; the replay fails after this
+    /// event.
+    Mismatch {
+        src_func: String,
+        src_op: masm::Op,
+        trace_func: String,
+        trace_op: masm::Op,
+    },
+
+    /// The source and trace disagreed but then agree again further on.  The ops in between, which
+    /// were skipped, are the divergent region.
+    Divergence {
+        func: String,
+        src_ops: Vec<masm::Op>,
+        trace_ops: Vec<masm::Op>,
+        depth: usize,
+    },
+
+    /// The trace ended with the executor reporting that `op` failed.  `call_stack` is the replay's
+    /// call stack at the failure and `call_stack_matches` whether it agrees with the report.
+    Failure {
+        func: String,
+        op: masm::Op,
+        depth: usize,
+        report: Box<failure::FailureReport>,
+        call_stack: Vec<String>,
+        call_stack_matches: bool,
+    },
+
+    /// Returned from the entry function.
+    EntryReturned,
+
+    /// Skipping the trace forward never arrived back at the current function.
+    FunctionNotFound { func: String },
+
+    /// The replay is complete.
+    EndOfTrace,
+}
+
+/// A replay session, walking the trace alongside the source it was executed from.  Iterates over
+/// the events of the replay.
+pub struct Replay {
+    options: ReplayOptions,
+    srcs: src_mgr::SourceManager,
+    trace: trace::TraceWindow,
+    entry_block: masm::BlockKey,
+
+    demangled_symbols: fxhash::FxHashMap<String, String>,
+    memory: memory::Memory,
+    coverage: coverage::Coverage,
+    trace_idx: usize,
+    prior_top_of_stack: [u64; 2],
+    stack_before_op: Vec<u64>,
+
+    // The loop and branch conditions dropped from the stack since the last op, which the trace
+    // doesn't show.
+    conds_popped: usize,
+
+    // After a stack mismatch, the stack we expected.  It's also tried as the stack before the next
+    // op, so a bad trace entry isn't reported twice.
+    expected_stack: Option<Vec<u64>>,
+    pending_trace_skip: bool,
+    skipped_callee: Option<String>,
+
+    // The model of the skipped callee, with the exec op, the stack before it and its depth.
+    pending_simulation: Option<(intrinsics::Simulation, masm::Op, Vec<u64>, usize)>,
+    pending_print_mem: Option<(u64, u64)>,
+
+    events: VecDeque<ReplayEvent>,
+    error: Option<anyhow::Error>,
+    started: bool,
+    finished: bool,
+}
+
+impl Iterator for Replay {
+    type Item = anyhow::Result<ReplayEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Some(Ok(event));
+            }
+
+            if let Some(err) = self.error.take() {
+                return Some(Err(err));
+            }
+
+            if self.finished {
+                return None;
+            }
+
+            if let Err(err) = self.step() {
+                self.error = Some(err);
+                self.finished = true;
+            }
+        }
+    }
+}
+
+impl Replay {
+    pub fn new(
+        src_items: masm::SourceBlocks,
+        trace: impl Iterator<Item = anyhow::Result<trace::Trace>> + 'static,
+        options: ReplayOptions,
+    ) -> anyhow::Result<Self> {
+        let mut trace = trace::TraceWindow::new(Box::new(trace));
+        trace.advance_to(0)?;
+
+        let mut srcs = src_mgr::SourceManager::new(src_items);
+        let (entry_block, entry_func) = srcs.set_entry(trace.get(0), &options.entry_func)?;
+
+        let mut demangled_symbols = fxhash::FxHashMap::default();
+        let mut trace_idx = 0;
+        if options.entry_func.is_some() {
+            // We need to skip along the trace until we hit the entry.
+            loop {
+                trace.advance_to(trace_idx)?;
+                match trace.get(trace_idx) {
+                    Some(trace_item)
+                        if symbol_name(&mut demangled_symbols, &trace_item.func) == entry_func =>
+                    {
+                        break;
+                    }
+                    Some(_) => trace_idx += 1,
+                    None => {
+                        anyhow::bail!("Failed to find entry function in the trace: {entry_func}")
+                    }
+                }
+            }
+        }
+
+        srcs.inc_indent();
+
+        let memory = memory::Memory::with_logging(options.watch.clone(), options.check_uninit);
+
+        Ok(Self {
+            options,
+            srcs,
+            trace,
+            entry_block,
+            demangled_symbols,
+            memory,
+            coverage: coverage::Coverage::default(),
+            trace_idx,
+            prior_top_of_stack: [0, 0],
+            stack_before_op: Vec::new(),
+            conds_popped: 0,
+            expected_stack: None,
+            pending_trace_skip: false,
+            skipped_callee: None,
+            pending_simulation: None,
+            pending_print_mem: None,
+            events: VecDeque::new(),
+            error: None,
+            started: false,
+            finished: false,
+        })
+    }
+
+    /// The source manager, positioned at the next op to be replayed.
+    pub fn source(&self) -> &src_mgr::SourceManager {
+        &self.srcs
+    }
+
+    /// The next trace event to be replayed.
+    pub fn current_trace(&self) -> Option<&trace::Trace> {
+        self.trace.get(self.trace_idx)
+    }
+
+    /// The memory as modelled from the trace so far.
+    pub fn memory(&self) -> &memory::Memory {
+        &self.memory
+    }
+
+    /// Journal the changes to the memory, to be taken after each event.
+    pub(crate) fn journal_memory(&mut self) {
+        self.memory.keep_journal();
+    }
+
+    /// The changes to the memory journaled since last taken.
+    pub(crate) fn take_memory_changes(&mut self) -> Vec<memory::FeltChange> {
+        self.memory.take_changes()
+    }
+
+    /// The source coverage of the replay so far.
+    pub fn coverage(&self) -> &coverage::Coverage {
+        &self.coverage
+    }
+
+    /// The index of the next trace event to be replayed.
+    pub fn trace_idx(&self) -> usize {
+        self.trace_idx
+    }
+
+    fn step(&mut self) -> anyhow::Result<()> {
+        if !self.started {
+            self.started = true;
+            self.events.push_back(ReplayEvent::Entry {
+                func: self.srcs.get_src_func_name()?.clone(),
+            });
+
+            return Ok(());
+        }
+
+        if !self.step_inner()? {
+            if self.trace.get(self.trace_idx).is_none() {
+                self.push_failure()?;
+            }
+
+            self.events.push_back(ReplayEvent::EndOfTrace);
+            self.finished = true;
+        }
+
+        Ok(())
+    }
+
+    fn step_inner(&mut self) -> anyhow::Result<bool> {
+        self.trace.advance_to(self.trace_idx)?;
+
+        // A loop body's final op may be a skipped call, which must return before the condition it
+        // leaves can be read.  Other skips wait until we've left any finished procedure, to know
+        // which procedure the callee returns to.
+        if self.pending_trace_skip && self.srcs.at_loop_body_end() && !self.finish_trace_skip()? {
+            return Ok(false);
+        }
+
+        // Synchronise the src mgr with the trace in the case of returning from blocks first.  If
+        // we're at the end of a loop body then the condition is whatever the last op left on top.
+        let loop_cond = self
+            .prev_trace()
+            .is_some_and(|prev_trace| prev_trace.stack[0] != 0);
+
+        if let Some(leave) = self.srcs.check_leave(loop_cond)? {
+            let frame = match leave {
+                src_mgr::Leave::Return(frame) => frame,
+                src_mgr::Leave::Iterate(block) => {
+                    if let src_mgr::BlockType::LoopBody(_) = block {
+                        self.conds_popped += 1;
+                    }
+                    self.events.push_back(ReplayEvent::Iterate {
+                        block,
+                        depth: self.srcs.depth() - 1,
+                    });
+
+                    return Ok(true);
+                }
+            };
+
+            match frame {
+                src_mgr::BlockType::Start => {
+                    self.events.push_back(ReplayEvent::EntryReturned);
+
+                    if self.options.repeat && self.trace.get(self.trace_idx).is_some() {
+                        self.srcs.reset_entry(self.entry_block);
+                        self.pending_trace_skip = true;
+                        self.memory.clear();
+
+                        self.events.push_back(ReplayEvent::Entry {
+                            func: self.srcs.get_src_func_name()?.clone(),
+                        });
+                        self.srcs.inc_indent();
+                    } else {
+                        return Ok(false);
+                    }
+                }
+
+                src_mgr::BlockType::Exec => {
+                    self.events.push_back(ReplayEvent::Return {
+                        func: self.srcs.get_src_func_name()?.clone(),
+                    });
+                }
+
+                src_mgr::BlockType::ThenBlock { .. }
+                | src_mgr::BlockType::ElseBlock
+                | src_mgr::BlockType::LoopBody(_)
+                | src_mgr::BlockType::RepeatBody(..) => {
+                    if let src_mgr::BlockType::LoopBody(_) = frame {
+                        self.conds_popped += 1;
+                    }
+                    self.srcs.dec_indent();
+                    self.events.push_back(ReplayEvent::BlockEnd {
+                        block: frame,
+                        depth: self.srcs.depth(),
+                    });
+                }
+            }
+
+            return Ok(true);
+        }
+
+        if self.pending_trace_skip && !self.finish_trace_skip()? {
+            return Ok(false);
+        }
+
+        self.push_pending_print_mem();
+        self.perform_io(self.trace_idx);
+
+        let Some(trace::Trace {
+            func,
+            op,
+            cycle,
+            total,
+            stack,
+            ..
+        }) = self.trace.get(self.trace_idx)
+        else {
+            // End of trace.
+            return Ok(false);
+        };
+
+        if self.options.check_semantics && *cycle == 1 {
+            self.stack_before_op = self
+                .prev_trace()
+                .map(|prev_trace| prev_trace.stack.clone())
+                .unwrap_or_default();
+        }
+
+        if cycle != total {
+            // Skip the intermediate micro-ops.
+            self.trace_idx += 1;
+            return Ok(true);
+        }
+
+        let src_pos = self.srcs.position();
+        let src_op = self.srcs.get_src_op();
+
+        if src_op.opcode() == Some("trace") {
+            // Skip `trace` ops in the source; they're not in the actual trace.
+            self.coverage.hit(src_pos);
+            self.srcs.next_op();
+            return Ok(true);
+        }
+
+        let prior_top_of_stack = &mut self.prior_top_of_stack;
+        prior_top_of_stack[1] = prior_top_of_stack[0];
+        prior_top_of_stack[0] = stack[0];
+
+        let depth = self.srcs.depth();
+
+        // Usually the op just matches; we'll assume it's all lined up.
+        if src_op == op {
+            let func = display_name(&mut self.demangled_symbols, self.options.raw_symbols, func);
+
+            self.events.push_back(ReplayEvent::OpExecuted {
+                func: func.to_string(),
+                op: op.clone(),
+                pos: src_pos,
+                cycle: self.trace_idx,
+                cycles: *total,
+                stack: stack.clone(),
+                depth,
+            });
+
+            if let Some(addr) = self.memory.take_last_access() {
+                self.events.push_back(
+                    self.memory
+                        .access_event(addr, memory::native_ptr_byte_addr(addr, 0)),
+                );
+            }
+
+            if self.options.check_semantics {
+                let (func, op, stack) = (func.to_string(), op.clone(), stack.clone());
+                self.check_semantics(func, &op, &stack);
+            }
+            self.conds_popped = 0;
+
+            self.coverage.hit(src_pos);
+            self.srcs.next_op();
+            self.trace_idx += 1;
+
+            return Ok(true);
+        }
+
+        // We have a mismatch; could be a call or conditional.
+        match src_op {
+            masm::Op::Op { opcode, arg } => {
+                if opcode == "exec" || opcode == "call" {
+                    self.coverage.hit(src_pos);
+
+                    let callee_func_name =
+                        &arg.as_ref().expect("CALL/EXEC must have an argument")[2..];
+
+                    // Take note of memory I/O.  The intrinsics take a native pointer, i.e., a
+                    // felt address and byte offset, from the stack as it was before the call.
+                    self.pending_print_mem = memory::is_byte_addressed_intrinsic(callee_func_name)
+                        .then(|| self.prev_trace())
+                        .flatten()
+                        .map(|prev_trace| {
+                            let (addr, offset) = (prev_trace.stack[0], prev_trace.stack[1]);
+                            (addr, memory::native_ptr_byte_addr(addr, offset))
+                        });
+
+                    let skipped_event = ReplayEvent::Skipped {
+                        op: src_op.clone(),
+                        pos: src_pos,
+                        callee: callee_func_name.to_string(),
+                        depth,
+                    };
+
+                    if let Some(callee_block_key) = self.srcs.find_block_key(callee_func_name) {
+                        // It seems maybe functions beginning with '__' are not actually run, or
+                        // traced, or... not sure.
+
+                        if callee_func_name
+                            .split("::")
+                            .last()
+                            .unwrap()
+                            .starts_with("__")
+                        {
+                            self.events.push_back(skipped_event);
+
+                            self.srcs.next_op();
+                        } else {
+                            let op = src_op.clone();
+                            self.srcs.enter(src_mgr::BlockType::Exec, callee_block_key);
+
+                            self.events.push_back(ReplayEvent::Enter {
+                                op,
+                                pos: src_pos,
+                                callee: self.srcs.get_src_func_name()?.clone(),
+                                depth,
+                            });
+                        }
+                    } else {
+                        // Skip the unknown (probably intrinsic) function until it returns,
+                        // simulating it if we can.
+                        let stack = self
+                            .prev_trace()
+                            .and_then(|prev_trace| prev_trace.stack.get(self.conds_popped..))
+                            .unwrap_or_default()
+                            .to_vec();
+                        self.pending_simulation =
+                            intrinsics::simulate(callee_func_name, &stack, &self.memory)
+                                .map(|simulation| (simulation, src_op.clone(), stack, depth));
+
+                        self.events.push_back(skipped_event);
+                        self.conds_popped = 0;
+                        self.expected_stack = None;
+
+                        // We could be at the end of a function, so the function we're actually
+                        // skipping to is not this one, but the caller.  So we need to know that
+                        // function name before we can skip.
+                        self.pending_trace_skip = true;
+                        self.skipped_callee = Some(callee_func_name.to_string());
+
+                        // Skip the exec to unknown too.
+                        self.srcs.next_op();
+                    }
+                } else {
+                    let mismatch = ReplayEvent::Mismatch {
+                        src_func: self.srcs.get_src_func_name()?.clone(),
+                        src_op: src_op.clone(),
+                        trace_func: display_name(
+                            &mut self.demangled_symbols,
+                            self.options.raw_symbols,
+                            func,
+                        )
+                        .to_string(),
+                        trace_op: op.clone(),
+                    };
+
+                    if self.options.strict || !self.resync()? {
+                        self.events.push_back(mismatch);
+
+                        anyhow::bail!("Mismatched operations!");
+                    }
+                }
+            }
+
+            masm::Op::Conditional {
+                on_true,
+                then_block,
+                else_block,
+            } => {
+                let cond = prior_top_of_stack[1] != 0;
+
+                let on_true = *on_true;
+                let then_block = *then_block;
+                let else_block = *else_block;
+
+                self.conds_popped += 1;
+                self.events.push_back(ReplayEvent::BranchTaken {
+                    branch: Branch::If {
+                        on_true,
+                        has_else: else_block.is_some(),
+                    },
+                    taken: cond == on_true,
+                    depth,
+                });
+                self.coverage.branch(src_pos, cond == on_true);
+
+                if cond != on_true {
+                    if let Some(else_block) = else_block {
+                        self.srcs.inc_indent();
+                        self.srcs.enter(src_mgr::BlockType::ElseBlock, else_block);
+                    } else {
+                        self.srcs.next_op();
+                    }
+                } else {
+                    self.srcs.inc_indent();
+                    self.srcs.enter(
+                        src_mgr::BlockType::ThenBlock {
+                            has_else: else_block.is_some(),
+                        },
+                        then_block,
+                    );
+                }
+            }
+
+            masm::Op::Loop(body_block_key) => {
+                let cond = prior_top_of_stack[1] != 0;
+
+                let body_block_key = *body_block_key;
+
+                self.conds_popped += 1;
+                self.events.push_back(ReplayEvent::BranchTaken {
+                    branch: Branch::While,
+                    taken: cond,
+                    depth,
+                });
+                self.coverage.branch(src_pos, cond);
+
+                if !cond {
+                    self.srcs.next_op();
+                } else {
+                    self.srcs.inc_indent();
+                    self.srcs
+                        .enter(src_mgr::BlockType::LoopBody(1), body_block_key);
+                }
+            }
+
+            masm::Op::Repeat(count, body_block_key) => {
+                let count = *count;
+                let body_block_key = *body_block_key;
+
+                self.events.push_back(ReplayEvent::BranchTaken {
+                    branch: Branch::Repeat(count),
+                    taken: count != 0,
+                    depth,
+                });
+                self.coverage.hit(src_pos);
+
+                if count == 0 {
+                    self.srcs.next_op();
+                } else {
+                    self.srcs.inc_indent();
+                    self.srcs
+                        .enter(src_mgr::BlockType::RepeatBody(1, count), body_block_key);
+                }
+            }
+        }
+
+        Ok(true)
+    }
+
+    // Skip the trace along until it arrives back at the current function.  Returns false if it
+    // never does.
+    fn finish_trace_skip(&mut self) -> anyhow::Result<bool> {
+        let ret_func_str = self.srcs.get_src_func_name()?.clone();
+        let mut skipped_cycles = 0;
+        loop {
+            skipped_cycles += 1;
+            self.perform_io(self.trace_idx);
+            self.trace_idx += 1;
+            self.trace.advance_to(self.trace_idx)?;
+
+            let Some(trace_item) = self.trace.get(self.trace_idx) else {
+                if let Some(callee) = self.skipped_callee.take() {
+                    self.push_skipped_cycles(callee, skipped_cycles);
+                }
+                self.events
+                    .push_back(ReplayEvent::FunctionNotFound { func: ret_func_str });
+                return Ok(false);
+            };
+
+            let func = symbol_name(&mut self.demangled_symbols, &trace_item.func);
+
+            if func == ret_func_str {
+                break;
+            }
+        }
+
+        if let Some(callee) = self.skipped_callee.take() {
+            self.push_skipped_cycles(callee, skipped_cycles);
+        }
+        if let Some((simulation, op, stack, depth)) = self.pending_simulation.take() {
+            self.finish_simulation(simulation, op, &stack, depth)?;
+        }
+        self.pending_trace_skip = false;
+
+        Ok(true)
+    }
+
+    // Having skipped the trace past a callee, the previous trace item is its final cycle.
+    fn push_skipped_cycles(&mut self, callee: String, cycles: u64) {
+        let stack = self
+            .prev_trace()
+            .map(|prev_trace| prev_trace.stack.clone())
+            .unwrap_or_default();
+        self.events.push_back(ReplayEvent::SkippedCycles {
+            callee,
+            cycles,
+            cycle: self.trace_idx.saturating_sub(1),
+            stack,
+        });
+    }
+
+    // Report the memory accessed by a skipped intrinsic, directly after it's returned.
+    fn push_pending_print_mem(&mut self) {
+        if let Some((addr, byte_addr)) = self.pending_print_mem.take() {
+            self.events
+                .push_back(self.memory.access_event(addr, byte_addr));
+        }
+    }
+
+    // Update the memory with the effect of the traced VM op, reporting any accesses logged.
+    fn perform_io(&mut self, trace_idx: usize) {
+        self.memory.perform_io(
+            &mut self.events,
+            trace_idx,
+            self.trace.get(trace_idx),
+            trace_idx.checked_sub(1).and_then(|idx| self.trace.get(idx)),
+        );
+
+        for access in self.memory.take_accesses() {
+            let Some(trace_item) = self.trace.get(trace_idx) else {
+                break;
+            };
+            let func = display_name(
+                &mut self.demangled_symbols,
+                self.options.raw_symbols,
+                &trace_item.func,
+            )
+            .to_string();
+
+            if access.uninit {
+                self.events.push_back(ReplayEvent::UninitRead {
+                    addr: access.addr,
+                    cycle: trace_idx,
+                    func: func.clone(),
+                    loaded: access.new,
+                });
+            }
+            if access.watched {
+                self.events.push_back(ReplayEvent::MemoryWatch {
+                    op: access.op,
+                    addr: access.addr,
+                    cycle: trace_idx,
+                    func,
+                    old: access.old,
+                    new: access.new,
+                });
+            }
+        }
+    }
+
+    // Record the memory written by a simulated call which has just returned, and check its results
+    // against the trace.
+    fn finish_simulation(
+        &mut self,
+        simulation: intrinsics::Simulation,
+        op: masm::Op,
+        stack: &[u64],
+        depth: usize,
+    ) -> anyhow::Result<()> {
+        let traced = self
+            .prev_trace()
+            .map(|prev_trace| prev_trace.stack.clone())
+            .unwrap_or_default();
+
+        for &(addr, val) in &simulation.writes {
+            self.memory.store(addr, &[val]);
+        }
+
+        self.events.push_back(ReplayEvent::Simulated {
+            call: simulation.call(&traced),
+            depth,
+        });
+        self.push_pending_print_mem();
+
+        if self.options.check_semantics {
+            let top = |stack: &[u64]| stack[..stack.len().min(semantics::STACK_DEPTH)].to_vec();
+            let expected = simulation.expected_stack(stack, &traced);
+            if top(&expected) != top(&traced) {
+                self.events.push_back(ReplayEvent::StackMismatch {
+                    func: self.srcs.get_src_func_name()?.clone(),
+                    op,
+                    expected: top(&expected),
+                    traced: top(&traced),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    // Compare the stack after an op with that expected from its semantics, given the stack before
+    // it less any conditions consumed by control flow.
+    fn check_semantics(&mut self, func: String, op: &masm::Op, stack: &[u64]) {
+        let top = |stack: &[u64]| stack[..stack.len().min(semantics::STACK_DEPTH)].to_vec();
+
+        let priors = [
+            Some(self.stack_before_op.clone()),
+            self.expected_stack.take(),
+        ];
+        let mut expected = priors.into_iter().flatten().filter_map(|prior| {
+            semantics::eval(op, prior.get(self.conds_popped..).unwrap_or_default())
+        });
+
+        let Some(traced_expected) = expected.next() else {
+            return;
+        };
+        if top(&traced_expected) == top(stack) || expected.any(|exp| top(&exp) == top(stack)) {
+            return;
+        }
+
+        self.events.push_back(ReplayEvent::StackMismatch {
+            func,
+            op: op.clone(),
+            expected: top(&traced_expected),
+            traced: top(stack),
+        });
+        self.expected_stack = Some(traced_expected);
+    }
+
+    // If the executor reported a failure after the last traced cycle then the failing op is the
+    // one we've arrived at.
+    fn push_failure(&mut self) -> anyhow::Result<()> {
+        let Some(report) = self
+            .prev_trace()
+            .and_then(|last_trace| last_trace.failure.clone())
+        else {
+            return Ok(());
+        };
+
+        // The report is matched against the call stack by its demangled names.
+        let mut report = report;
+        let raw_stack_trace = std::mem::take(&mut report.stack_trace);
+        report.stack_trace = raw_stack_trace
+            .iter()
+            .map(|func| symbol_name(&mut self.demangled_symbols, func).to_string())
+            .collect();
+
+        let call_stack = self.srcs.call_stack_funcs();
+        let call_stack_matches = report.matches_call_stack(&call_stack);
+        if self.options.raw_symbols {
+            report.stack_trace = raw_stack_trace;
+        }
+
+        self.events.push_back(ReplayEvent::Failure {
+            func: self.srcs.get_src_func_name()?.clone(),
+            op: self.srcs.get_src_op().clone(),
+            depth: self.srcs.depth(),
+            report,
+            call_stack,
+            call_stack_matches,
+        });
+
+        Ok(())
+    }
+
+    // Skip the source and trace forward to where they agree again after a mismatch, reporting the
+    // gap between as a divergence.  Returns false if they don't agree again anywhere nearby.  Only
+    // the rest of the current block, up to any call, is searched, so there's no resyncing a
+    // mismatch at the end of a block or just before a call.
+    fn resync(&mut self) -> anyhow::Result<bool> {
+        let src_func = self.srcs.get_src_func_name()?.clone();
+
+        // Only straight line source can be matched; the trace doesn't show control flow or calls.
+        let remaining_ops = self.srcs.remaining_ops();
+        let straight_line_len = remaining_ops
+            .iter()
+            .take_while(|op| {
+                op.opcode()
+                    .is_some_and(|opcode| opcode != "exec" && opcode != "call")
+            })
+            .count();
+        let src_ops = &remaining_ops[..straight_line_len];
+
+        // Gather the final cycles of the upcoming traced ops.
+        let mut op_trace_idcs = Vec::new();
+        let mut trace_idx = self.trace_idx;
+        while op_trace_idcs.len() < resync::LOOKAHEAD_OPS {
+            self.trace.read_to(trace_idx)?;
+            let Some(trace_item) = self.trace.get(trace_idx) else {
+                break;
+            };
+            if trace_item.cycle == trace_item.total {
+                op_trace_idcs.push(trace_idx);
+            }
+            trace_idx += 1;
+        }
+
+        let trace_items = op_trace_idcs
+            .iter()
+            .filter_map(|idx| self.trace.get(*idx))
+            .collect::<Vec<_>>();
+        let trace_ops = trace_items
+            .iter()
+            .map(|trace_item| {
+                (symbol_name(&mut self.demangled_symbols, &trace_item.func) == src_func)
+                    .then_some(&trace_item.op)
+            })
+            .collect::<Vec<_>>();
+
+        let Some((src_skip, trace_skip)) = resync::find_resync(src_ops, &trace_ops) else {
+            return Ok(false);
+        };
+
+        self.events.push_back(ReplayEvent::Divergence {
+            func: src_func,
+            src_ops: src_ops[..src_skip].to_vec(),
+            trace_ops: trace_items[..trace_skip]
+                .iter()
+                .map(|trace_item| trace_item.op.clone())
+                .collect(),
+            depth: self.srcs.depth(),
+        });
+
+        for _ in 0..src_skip {
+            self.srcs.next_op();
+        }
+
+        // Keep the memory and the recent stack tops up to date through the skipped trace.
+        let resync_trace_idx = op_trace_idcs[trace_skip];
+        for trace_idx in self.trace_idx + 1..resync_trace_idx {
+            self.trace.advance_to(trace_idx)?;
+            self.perform_io(trace_idx);
+
+            if let Some(trace_item) = self.trace.get(trace_idx)
+                && trace_item.cycle == trace_item.total
+            {
+                self.prior_top_of_stack = [trace_item.stack[0], self.prior_top_of_stack[0]];
+            }
+        }
+        self.trace_idx = resync_trace_idx;
+
+        Ok(true)
+    }
+
+    fn prev_trace(&self) -> Option<&trace::Trace> {
+        self.trace_idx
+            .checked_sub(1)
+            .and_then(|prev_idx| self.trace.get(prev_idx))
+    }
+}
+
+// The name to match against the source for a traced symbol.  It's demangled, falling back to the
+// raw symbol if it can't be.
+fn symbol_name<'a>(
+    demangled_symbols: &'a mut fxhash::FxHashMap<String, String>,
+    func: &'a str,
+) -> &'a str {
+    if !demangled_symbols.contains_key(func) {
+        let demangled = demangle::demangle(func).unwrap_or_else(|_| func.to_string());
+        demangled_symbols.insert(func.to_string(), demangled);
+    }
+
+    &demangled_symbols[func]
+}
+
+// The name to show for a traced symbol; the raw symbol if requested, else demangled.
+fn display_name<'a>(
+    demangled_symbols: &'a mut fxhash::FxHashMap<String, String>,
+    raw_symbols: bool,
+    func: &'a str,
+) -> &'a str {
+    if raw_symbols {
+        func
+    } else {
+        symbol_name(demangled_symbols, func)
+    }
+}
+
+// vim:fdl=3
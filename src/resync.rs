@@ -0,0 +1,49 @@
+use crate::masm;
+
+/// How many traced ops to look ahead for somewhere to resynchronise.
+pub(crate) const LOOKAHEAD_OPS: usize = 64;
+
+/// How many consecutive ops must agree for the source and trace to be back in sync.  Fewer will do
+/// if the source block ends first.
+const CONFIRM_OPS: usize = 3;
+
+/// Find the nearest point after a mismatch where the source and trace agree again, returned as the
+/// number of source ops and traced ops to skip to get there.
+///
+/// `src_ops` are the straight line ops from the mismatch to the end of the source block.
+/// `trace_ops` are the traced ops from the mismatch, with `None` for those which can't be resync
+/// points, e.g., because they're in a different procedure.
+pub(crate) fn find_resync(
+    src_ops: &[masm::Op],
+    trace_ops: &[Option<&masm::Op>],
+) -> Option<(usize, usize)> {
+    let max_dist = src_ops.len() + trace_ops.len();
+
+    // Prefer the smallest gap overall, then the fewest skipped source ops.
+    (1..max_dist).find_map(|dist| {
+        (0..=dist)
+            .map(|src_skip| (src_skip, dist - src_skip))
+            .find(|&(src_skip, trace_skip)| agrees_at(src_ops, trace_ops, src_skip, trace_skip))
+    })
+}
+
+fn agrees_at(
+    src_ops: &[masm::Op],
+    trace_ops: &[Option<&masm::Op>],
+    src_skip: usize,
+    trace_skip: usize,
+) -> bool {
+    let src_ops = src_ops.get(src_skip..).unwrap_or_default();
+    let trace_ops = trace_ops.get(trace_skip..).unwrap_or_default();
+
+    let confirm_len = src_ops.len().min(CONFIRM_OPS);
+    confirm_len > 0
+        && trace_ops.len() >= confirm_len
+        && src_ops
+            .iter()
+            .zip(trace_ops)
+            .take(confirm_len)
+            .all(|(src_op, trace_op)| *trace_op == Some(src_op))
+}
+
+// vim:fdl=3
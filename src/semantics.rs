@@ -0,0 +1,389 @@
+use crate::masm::Op;
+
+/// The Goldilocks prime, 2^64 - 2^32 + 1, which felts are modulo.
+pub const FIELD_MODULUS: u64 = 0xffff_ffff_0000_0001;
+
+/// The depth of the operand stack proper.  The VM pads it with zeros so it's never shallower, and
+/// anything deeper is in the overflow table, which the trace doesn't show reliably.
+pub const STACK_DEPTH: usize = 16;
+
+const U32_MODULUS: u64 = 1 << 32;
+
+/// The stack expected after `op` given the stack before it, top first.  Only ops whose results
+/// are entirely determined by the stack are evaluated; `None` is returned for others, e.g.,
+/// memory loads and advice, and for ops which would fail.
+pub fn eval(op: &Op, stack: &[u64]) -> Option<Vec<u64>> {
+    let Op::Op { opcode, arg } = op else {
+        return None;
+    };
+
+    let mut stack = Stack(stack.to_vec());
+    let imm = arg.as_deref().map(parse_imm);
+
+    match opcode.as_str() {
+        "nop" => {}
+
+        "push" => {
+            // Each of several values is pushed in turn.
+            for val in arg.as_ref()?.split('.') {
+                stack.push(parse_imm(val)?);
+            }
+        }
+        "pad" => stack.push(0),
+        "padw" => (0..4).for_each(|_| stack.push(0)),
+
+        "drop" => {
+            stack.pop()?;
+        }
+        "dropw" => {
+            stack.pop_n(4)?;
+        }
+        "dup" => stack.push(*stack.0.get(index(imm, 0)?)?),
+        "dupw" => {
+            let start = index(imm, 0)? * 4;
+            let word = stack.0.get(start..start + 4)?.to_vec();
+            word.into_iter().rev().for_each(|val| stack.push(val));
+        }
+        "swap" => {
+            let idx = index(imm, 1)?;
+            stack.check_depth(idx + 1)?;
+            stack.0.swap(0, idx);
+        }
+        "swapw" => {
+            let idx = index(imm, 1)? * 4;
+            stack.check_depth(idx + 4)?;
+            for offs in 0..4 {
+                stack.0.swap(offs, idx + offs);
+            }
+        }
+        "swapdw" => {
+            stack.check_depth(16)?;
+            stack.0[0..16].rotate_left(8);
+        }
+        "movup" => {
+            let idx = index(imm, 0)?;
+            stack.check_depth(idx + 1)?;
+            stack.0[0..=idx].rotate_right(1);
+        }
+        "movdn" => {
+            let idx = index(imm, 0)?;
+            stack.check_depth(idx + 1)?;
+            stack.0[0..=idx].rotate_left(1);
+        }
+        "movupw" => {
+            let idx = index(imm, 0)? * 4;
+            stack.check_depth(idx + 4)?;
+            stack.0[0..idx + 4].rotate_right(4);
+        }
+        "movdnw" => {
+            let idx = index(imm, 0)? * 4;
+            stack.check_depth(idx + 4)?;
+            stack.0[0..idx + 4].rotate_left(4);
+        }
+        "reversew" => {
+            stack.check_depth(4)?;
+            stack.0[0..4].reverse();
+        }
+
+        // [c, b, a, ...] -> [a, b, ...] if c else [b, a, ...]
+        "cswap" => {
+            let cond = stack.pop_bool()?;
+            stack.check_depth(2)?;
+            if cond {
+                stack.0.swap(0, 1);
+            }
+        }
+        "cswapw" => {
+            let cond = stack.pop_bool()?;
+            stack.check_depth(8)?;
+            if cond {
+                stack.0[0..8].rotate_left(4);
+            }
+        }
+
+        // [c, b, a, ...] -> [b, ...] if c else [a, ...]
+        "cdrop" => {
+            let cond = stack.pop_bool()?;
+            let b = stack.pop()?;
+            let a = stack.pop()?;
+            stack.push(if cond { b } else { a });
+        }
+        "cdropw" => {
+            let cond = stack.pop_bool()?;
+            let b = stack.pop_n(4)?;
+            let a = stack.pop_n(4)?;
+            let word = if cond { b } else { a };
+            word.into_iter().rev().for_each(|val| stack.push(val));
+        }
+
+        "assert" | "assertz" | "assert_eq" | "assert_eqw" => {
+            let count = match opcode.as_str() {
+                "assert_eq" => 2,
+                "assert_eqw" => 8,
+                _ => 1,
+            };
+            let vals = stack.pop_n(count)?;
+            let holds = match opcode.as_str() {
+                "assert" => vals[0] == 1,
+                "assertz" => vals[0] == 0,
+                _ => vals[0..count / 2] == vals[count / 2..],
+            };
+            if !holds {
+                return None;
+            }
+        }
+
+        "add" => binary(&mut stack, imm, false, |a, b| vec![add(a, b)])?,
+        "sub" => binary(&mut stack, imm, false, |a, b| vec![sub(a, b)])?,
+        "mul" => binary(&mut stack, imm, false, |a, b| vec![mul(a, b)])?,
+        "div" => {
+            if let Some(imm) = imm {
+                stack.push(imm?);
+            }
+            let b = stack.pop()?;
+            let a = stack.pop()?;
+            stack.push(mul(a, inv(b)?));
+        }
+        "neg" => {
+            let a = stack.pop()?;
+            stack.push(sub(0, a));
+        }
+        "inv" => {
+            let a = stack.pop()?;
+            stack.push(inv(a)?);
+        }
+        "incr" => {
+            let a = stack.pop()?;
+            stack.push(add(a, 1));
+        }
+        "pow2" => {
+            let a = stack.pop()?;
+            stack.push(1_u64.checked_shl(u32::try_from(a).ok().filter(|a| *a < 64)?)?);
+        }
+        "is_odd" => {
+            let a = stack.pop()?;
+            stack.push(a & 1);
+        }
+
+        "eq" => binary(&mut stack, imm, false, |a, b| vec![u64::from(a == b)])?,
+        "neq" => binary(&mut stack, imm, false, |a, b| vec![u64::from(a != b)])?,
+        "lt" => binary(&mut stack, imm, false, |a, b| vec![u64::from(a < b)])?,
+        "lte" => binary(&mut stack, imm, false, |a, b| vec![u64::from(a <= b)])?,
+        "gt" => binary(&mut stack, imm, false, |a, b| vec![u64::from(a > b)])?,
+        "gte" => binary(&mut stack, imm, false, |a, b| vec![u64::from(a >= b)])?,
+        "eqw" => {
+            stack.check_depth(8)?;
+            let eq = stack.0[0..4] == stack.0[4..8];
+            stack.push(u64::from(eq));
+        }
+
+        "not" => {
+            let a = stack.pop_bool()?;
+            stack.push(u64::from(!a));
+        }
+        "and" | "or" | "xor" => {
+            let b = stack.pop_bool()?;
+            let a = stack.pop_bool()?;
+            stack.push(u64::from(match opcode.as_str() {
+                "and" => a && b,
+                "or" => a || b,
+                _ => a ^ b,
+            }));
+        }
+
+        "u32assert" | "u32assert2" | "u32assertw" => {
+            let count = match opcode.as_str() {
+                "u32assert" => 1,
+                "u32assert2" => 2,
+                _ => 4,
+            };
+            stack.check_depth(count)?;
+            if stack.0[0..count].iter().any(|val| *val >= U32_MODULUS) {
+                return None;
+            }
+        }
+        "u32test" => stack.push(u64::from(*stack.0.first()? < U32_MODULUS)),
+        "u32cast" => {
+            let a = stack.pop()?;
+            stack.push(a % U32_MODULUS);
+        }
+        "u32split" => {
+            let a = stack.pop()?;
+            stack.push(a % U32_MODULUS);
+            stack.push(a >> 32);
+        }
+        "u32not" => {
+            let a = stack.pop_u32()?;
+            stack.push(u64::from(!(a as u32)));
+        }
+
+        "u32wrapping_add" => binary(&mut stack, imm, true, |a, b| vec![(a + b) % U32_MODULUS])?,
+        "u32wrapping_sub" => binary(&mut stack, imm, true, |a, b| {
+            vec![(a + U32_MODULUS - b) % U32_MODULUS]
+        })?,
+        "u32wrapping_mul" => binary(&mut stack, imm, true, |a, b| vec![(a * b) % U32_MODULUS])?,
+
+        // The overflow, borrow or high half is left on top.
+        "u32overflowing_add" => binary(&mut stack, imm, true, |a, b| {
+            let sum = a + b;
+            vec![sum >> 32, sum % U32_MODULUS]
+        })?,
+        "u32overflowing_sub" => binary(&mut stack, imm, true, |a, b| {
+            vec![u64::from(a < b), (a + U32_MODULUS - b) % U32_MODULUS]
+        })?,
+        "u32overflowing_mul" => binary(&mut stack, imm, true, |a, b| {
+            let product = a * b;
+            vec![product >> 32, product % U32_MODULUS]
+        })?,
+
+        // [b, a, ...] -> [a % b, a / b, ...]
+        "u32divmod" | "u32div" | "u32mod" => {
+            if let Some(imm) = imm {
+                stack.push(imm?);
+            }
+            let b = stack.pop_u32()?;
+            let a = stack.pop_u32()?;
+            if b == 0 {
+                return None;
+            }
+            if opcode != "u32mod" {
+                stack.push(a / b);
+            }
+            if opcode != "u32div" {
+                stack.push(a % b);
+            }
+        }
+
+        "u32and" => binary(&mut stack, imm, true, |a, b| vec![a & b])?,
+        "u32or" => binary(&mut stack, imm, true, |a, b| vec![a | b])?,
+        "u32xor" => binary(&mut stack, imm, true, |a, b| vec![a ^ b])?,
+        "u32shl" | "u32shr" | "u32rotl" | "u32rotr" => {
+            if let Some(imm) = imm {
+                stack.push(imm?);
+            }
+            let b = stack.pop_u32()?;
+            let a = stack.pop_u32()? as u32;
+            let shift = u32::try_from(b).ok().filter(|b| *b < 32)?;
+            stack.push(u64::from(match opcode.as_str() {
+                "u32shl" => a << shift,
+                "u32shr" => a >> shift,
+                "u32rotl" => a.rotate_left(shift),
+                _ => a.rotate_right(shift),
+            }));
+        }
+        "u32lt" => binary(&mut stack, imm, true, |a, b| vec![u64::from(a < b)])?,
+        "u32lte" => binary(&mut stack, imm, true, |a, b| vec![u64::from(a <= b)])?,
+        "u32gt" => binary(&mut stack, imm, true, |a, b| vec![u64::from(a > b)])?,
+        "u32gte" => binary(&mut stack, imm, true, |a, b| vec![u64::from(a >= b)])?,
+        "u32min" => binary(&mut stack, imm, true, |a, b| vec![a.min(b)])?,
+        "u32max" => binary(&mut stack, imm, true, |a, b| vec![a.max(b)])?,
+
+        _ => return None,
+    }
+
+    // Popping below the minimum depth shifts zeros in from the bottom.
+    if stack.0.len() < STACK_DEPTH {
+        stack.0.resize(STACK_DEPTH, 0);
+    }
+    Some(stack.0)
+}
+
+// The operand stack, top first.
+struct Stack(Vec<u64>);
+
+impl Stack {
+    fn push(&mut self, val: u64) {
+        self.0.insert(0, val);
+    }
+
+    fn pop(&mut self) -> Option<u64> {
+        (!self.0.is_empty()).then(|| self.0.remove(0))
+    }
+
+    fn pop_n(&mut self, count: usize) -> Option<Vec<u64>> {
+        self.check_depth(count)?;
+        Some(self.0.drain(0..count).collect())
+    }
+
+    fn pop_bool(&mut self) -> Option<bool> {
+        match self.pop()? {
+            0 => Some(false),
+            1 => Some(true),
+            _ => None,
+        }
+    }
+
+    fn pop_u32(&mut self) -> Option<u64> {
+        self.pop().filter(|val| *val < U32_MODULUS)
+    }
+
+    fn check_depth(&self, depth: usize) -> Option<()> {
+        (self.0.len() >= depth).then_some(())
+    }
+}
+
+// Apply a binary op, the second operand of which may be an immediate, e.g., `add.1`.  The results
+// are pushed in reverse, so the first is left on top.
+fn binary(
+    stack: &mut Stack,
+    imm: Option<Option<u64>>,
+    is_u32: bool,
+    f: fn(u64, u64) -> Vec<u64>,
+) -> Option<()> {
+    if let Some(imm) = imm {
+        stack.push(imm?);
+    }
+    let (b, a) = if is_u32 {
+        (stack.pop_u32()?, stack.pop_u32()?)
+    } else {
+        (stack.pop()?, stack.pop()?)
+    };
+    f(a, b).into_iter().rev().for_each(|val| stack.push(val));
+    Some(())
+}
+
+fn index(imm: Option<Option<u64>>, default: usize) -> Option<usize> {
+    match imm {
+        Some(imm) => usize::try_from(imm?).ok(),
+        None => Some(default),
+    }
+}
+
+fn parse_imm(imm: &str) -> Option<u64> {
+    let val = match imm.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok()?,
+        None => imm.parse().ok()?,
+    };
+    (val < FIELD_MODULUS).then_some(val)
+}
+
+pub fn add(a: u64, b: u64) -> u64 {
+    ((u128::from(a) + u128::from(b)) % u128::from(FIELD_MODULUS)) as u64
+}
+
+pub fn sub(a: u64, b: u64) -> u64 {
+    ((u128::from(a) + u128::from(FIELD_MODULUS) - u128::from(b)) % u128::from(FIELD_MODULUS)) as u64
+}
+
+pub fn mul(a: u64, b: u64) -> u64 {
+    ((u128::from(a) * u128::from(b)) % u128::from(FIELD_MODULUS)) as u64
+}
+
+/// The multiplicative inverse, by Fermat's little theorem.  Zero has none.
+pub fn inv(a: u64) -> Option<u64> {
+    if a == 0 {
+        return None;
+    }
+
+    let (mut base, mut exp, mut result) = (a, FIELD_MODULUS - 2, 1);
+    while exp != 0 {
+        if exp & 1 == 1 {
+            result = mul(result, base);
+        }
+        base = mul(base, base);
+        exp >>= 1;
+    }
+    Some(result)
+}
+
+// vim:fdl=3
@@ -1,8 +1,8 @@
 use crate::{masm, trace};
 
-const INDENT_AMOUNT: usize = 4;
+pub(crate) const INDENT_AMOUNT: usize = 4;
 
-pub(crate) struct SourceManager {
+pub struct SourceManager {
     srcs: masm::SourceBlocks,
     src_block_key: masm::BlockKey,
     pc: usize,
@@ -10,17 +10,35 @@ pub(crate) struct SourceManager {
     indent: usize,
 }
 
-pub(crate) enum BlockType {
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BlockType {
     Start,
     Exec,
-    TrueBlock,
-    FalseBlock,
+    ThenBlock { has_else: bool },
+    ElseBlock,
+    LoopBody(usize),
+    RepeatBody(usize, usize),
+}
+
+/// The position of an op in the source, as its block and index within that block.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SourcePos {
+    pub block: masm::BlockKey,
+    pub pc: usize,
+}
+
+pub enum Leave {
+    /// We ran off the end of a block and have returned to its parent.
+    Return(BlockType),
+
+    /// We ran off the end of a loop body and have gone around again.
+    Iterate(BlockType),
 }
 
 impl SourceManager {
     // TODO: Intialise with an entry so that we don't have an empty call stack or invalid
     // src_item_idx.
-    pub(crate) fn new(srcs: masm::SourceBlocks) -> Self {
+    pub fn new(srcs: masm::SourceBlocks) -> Self {
         Self {
             srcs,
             src_block_key: masm::BlockKey::default(),
@@ -30,14 +48,19 @@ impl SourceManager {
         }
     }
 
-    pub(crate) fn find_block_key(&self, func: &str) -> Option<masm::BlockKey> {
+    /// The parsed source blocks being replayed.
+    pub fn blocks(&self) -> &masm::SourceBlocks {
+        &self.srcs
+    }
+
+    pub fn find_block_key(&self, func: &str) -> Option<masm::BlockKey> {
         self.srcs
             .iter()
             .find(|(_, block)| block.name().map(|name| name == func).unwrap_or(false))
             .map(|(key, _)| key)
     }
 
-    pub(crate) fn fuzzy_find_block_key(&self, func: &str) -> Vec<masm::BlockKey> {
+    pub fn fuzzy_find_block_key(&self, func: &str) -> Vec<masm::BlockKey> {
         self.srcs
             .iter()
             .filter_map(|(key, block)| {
@@ -48,7 +71,7 @@ impl SourceManager {
             .collect()
     }
 
-    pub(crate) fn get_src_func_name(&self) -> anyhow::Result<&String> {
+    pub fn get_src_func_name(&self) -> anyhow::Result<&String> {
         // Default to the current block name.
         self.srcs[self.src_block_key]
             .name()
@@ -62,19 +85,63 @@ impl SourceManager {
             .ok_or_else(|| anyhow::anyhow!("Failed to find a current function name"))
     }
 
-    pub(crate) fn get_src_op(&self) -> &masm::Op {
+    /// The names of the procedures on the call stack, outermost first and ending with the current
+    /// one.
+    pub fn call_stack_funcs(&self) -> Vec<String> {
+        let mut funcs = Vec::new();
+
+        // Each frame records the block to return to, so the caller of an `exec` is the procedure
+        // which that block is within.
+        let mut caller = None;
+        for (frame, ret_block_key, _) in &self.call_stack {
+            if *frame == BlockType::Start {
+                caller = None;
+                continue;
+            }
+
+            if let Some(name) = self.srcs[*ret_block_key].name() {
+                caller = Some(name);
+            }
+            if *frame == BlockType::Exec {
+                funcs.extend(caller.cloned());
+            }
+        }
+
+        funcs.extend(self.get_src_func_name().ok().cloned());
+        funcs
+    }
+
+    pub fn get_src_op(&self) -> &masm::Op {
         self.srcs[self.src_block_key].op_at(self.pc)
     }
 
+    /// The ops from the current one to the end of the current block.
+    pub fn remaining_ops(&self) -> &[masm::Op] {
+        &self.srcs[self.src_block_key].ops()[self.pc..]
+    }
+
+    /// The position of the current op.
+    pub fn position(&self) -> SourcePos {
+        SourcePos {
+            block: self.src_block_key,
+            pc: self.pc,
+        }
+    }
+
     pub(crate) fn next_op(&mut self) {
         self.pc += 1;
     }
 
-    pub(crate) fn indent(&self) -> usize {
+    pub fn indent(&self) -> usize {
         self.indent
     }
 
-    pub(crate) fn indent_next(&self) -> usize {
+    /// The nesting depth of the current op, i.e., the indent in levels rather than spaces.
+    pub fn depth(&self) -> usize {
+        self.indent / INDENT_AMOUNT
+    }
+
+    pub fn indent_next(&self) -> usize {
         self.indent + INDENT_AMOUNT
     }
 
@@ -93,8 +160,37 @@ impl SourceManager {
         self.pc = 0;
     }
 
-    pub(crate) fn check_leave(&mut self) -> anyhow::Result<Option<BlockType>> {
+    /// Whether we've run off the end of a loop body.
+    pub(crate) fn at_loop_body_end(&self) -> bool {
+        self.pc >= self.srcs[self.src_block_key].len()
+            && matches!(self.call_stack.last(), Some((BlockType::LoopBody(_), ..)))
+    }
+
+    pub(crate) fn check_leave(&mut self, loop_cond: bool) -> anyhow::Result<Option<Leave>> {
         if self.pc >= self.srcs[self.src_block_key].len() {
+            // If this is a loop body and the condition still holds, or a repeat body with
+            // iterations to go, then start it again.  An empty loop body would never get anywhere
+            // in the trace, so it's left.
+            let body_is_empty = self.srcs[self.src_block_key].is_empty();
+            if let Some((frame, ..)) = self.call_stack.last_mut() {
+                let again = match frame {
+                    BlockType::LoopBody(iter) if loop_cond && !body_is_empty => {
+                        *iter += 1;
+                        true
+                    }
+                    BlockType::RepeatBody(iter, count) if *iter < *count => {
+                        *iter += 1;
+                        true
+                    }
+                    _ => false,
+                };
+
+                if again {
+                    self.pc = 0;
+                    return Ok(Some(Leave::Iterate(*frame)));
+                }
+            }
+
             // We've run off the end of this block.  Need to return.
             let (frame, ret_block_key, ret_pc) = self
                 .call_stack
@@ -104,7 +200,7 @@ impl SourceManager {
             self.src_block_key = ret_block_key;
             self.pc = ret_pc;
 
-            Ok(Some(frame))
+            Ok(Some(Leave::Return(frame)))
         } else {
             Ok(None)
         }
@@ -112,7 +208,7 @@ impl SourceManager {
 
     pub(crate) fn set_entry(
         &mut self,
-        trace: &[trace::Trace],
+        first_trace: Option<&trace::Trace>,
         entry_func: &Option<String>,
     ) -> anyhow::Result<(masm::BlockKey, String)> {
         let block_key = if let Some(entry_func) = entry_func {
@@ -132,9 +228,10 @@ impl SourceManager {
                 }
             }
         } else {
-            self.get_entry_func_block_key(trace).ok_or(anyhow::anyhow!(
-                "Failed to determine default entry function."
-            ))
+            self.get_entry_func_block_key(first_trace)
+                .ok_or(anyhow::anyhow!(
+                    "Failed to determine default entry function."
+                ))
         }?;
 
         // XXX: This could be re-thought.  A lot of re-fetching and cloning of strings going on.
@@ -150,7 +247,10 @@ impl SourceManager {
         self.indent = 0;
     }
 
-    fn get_entry_func_block_key(&self, trace: &[trace::Trace]) -> Option<masm::BlockKey> {
+    fn get_entry_func_block_key(
+        &self,
+        first_trace: Option<&trace::Trace>,
+    ) -> Option<masm::BlockKey> {
         // Get the first trace event.  If it's pointing to a `run` then that's our entry func.  If
         // it's pointing to an `init`, then replace `init` with `run` and try that.
 
@@ -163,7 +263,7 @@ impl SourceManager {
             }};
         }
 
-        if let Some(trace::Trace { func, .. }) = trace.first() {
+        if let Some(trace::Trace { func, .. }) = first_trace {
             if func.ends_with("::run") {
                 ret_if_found!(func)
             }
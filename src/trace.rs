@@ -1,10 +1,68 @@
-use crate::masm::Op;
+use std::collections::VecDeque;
 
+use crate::{failure::FailureReport, masm::Op};
+
+/// A single traced VM cycle.  Assembly ops made up of several VM ops are traced once per cycle,
+/// numbered `cycle` out of `total`.
 #[derive(Debug)]
-pub(crate) struct Trace {
-    pub(crate) func: String,
-    pub(crate) op: Op,
-    pub(crate) cycle: u64,
-    pub(crate) total: u64,
-    pub(crate) stack: Vec<u64>,
+pub struct Trace {
+    pub func: String,
+    pub op: Op,
+
+    /// The VM op executed for this cycle, e.g., `MStoreW`.
+    pub vm_op: String,
+    pub cycle: u64,
+    pub total: u64,
+    pub stack: Vec<u64>,
+
+    /// The executor's failure report, when execution failed straight after this cycle.
+    pub failure: Option<Box<FailureReport>>,
+}
+
+/// A sliding window over a streamed trace.  Only the current and previous events are kept, so
+/// the memory used doesn't grow with the length of the trace.
+pub(crate) struct TraceWindow {
+    source: Box<dyn Iterator<Item = anyhow::Result<Trace>>>,
+    window: VecDeque<Trace>,
+
+    // The index into the trace of the first event in the window.
+    base_idx: usize,
+}
+
+impl TraceWindow {
+    pub(crate) fn new(source: Box<dyn Iterator<Item = anyhow::Result<Trace>>>) -> Self {
+        Self {
+            source,
+            window: VecDeque::new(),
+            base_idx: 0,
+        }
+    }
+
+    /// Read the trace up to and including `idx`, and drop everything before `idx - 1`.
+    pub(crate) fn advance_to(&mut self, idx: usize) -> anyhow::Result<()> {
+        while self.base_idx + 1 < idx && !self.window.is_empty() {
+            self.window.pop_front();
+            self.base_idx += 1;
+        }
+
+        self.read_to(idx)
+    }
+
+    /// Read the trace up to and including `idx` without dropping anything, to look ahead.
+    pub(crate) fn read_to(&mut self, idx: usize) -> anyhow::Result<()> {
+        while self.base_idx + self.window.len() <= idx {
+            let Some(trace) = self.source.next() else {
+                break;
+            };
+            self.window.push_back(trace?);
+        }
+
+        Ok(())
+    }
+
+    /// Get the event at `idx`, if it's been read and not yet dropped.
+    pub(crate) fn get(&self, idx: usize) -> Option<&Trace> {
+        idx.checked_sub(self.base_idx)
+            .and_then(|window_idx| self.window.get(window_idx))
+    }
 }
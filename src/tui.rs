@@ -0,0 +1,250 @@
+use ratatui::{
+    Frame,
+    crossterm::event::{self, Event, KeyCode, KeyEventKind},
+    layout::{Constraint, Layout, Rect},
+    style::{Modifier, Style},
+    text::{Line, Text},
+    widgets::{Block, Paragraph},
+};
+
+use crate::{
+    debugger::{self, Debugger},
+    render,
+};
+
+const HELP: &str = "s step  n over  o out  b back  g<cycle> goto  r run  q quit";
+
+/// An interactive terminal UI for stepping through a replay.
+pub struct Tui {
+    debugger: Debugger,
+
+    // The digits typed so far after `g`, if waiting for a cycle to run to.
+    cycle_input: Option<String>,
+    status: String,
+}
+
+impl Tui {
+    pub fn new(debugger: Debugger) -> Self {
+        Self {
+            debugger,
+            cycle_input: None,
+            status: String::new(),
+        }
+    }
+
+    pub fn debugger(&self) -> &Debugger {
+        &self.debugger
+    }
+
+    /// Take over the terminal until the user quits.
+    pub fn run(mut self) -> anyhow::Result<()> {
+        let mut terminal = ratatui::init();
+        let result = (|| -> anyhow::Result<()> {
+            loop {
+                terminal.draw(|frame| self.draw(frame))?;
+                if let Event::Key(key) = event::read()?
+                    && key.kind == KeyEventKind::Press
+                    && !self.handle_key(key.code)
+                {
+                    return Ok(());
+                }
+            }
+        })();
+        ratatui::restore();
+        result
+    }
+
+    /// Act on a key press.  Returns false when the user wants to quit.
+    pub fn handle_key(&mut self, key: KeyCode) -> bool {
+        if let Some(input) = &mut self.cycle_input {
+            match key {
+                KeyCode::Char(digit) if digit.is_ascii_digit() => input.push(digit),
+                KeyCode::Backspace => {
+                    input.pop();
+                }
+                KeyCode::Enter => {
+                    let input = self.cycle_input.take().unwrap_or_default();
+                    match input.parse() {
+                        Ok(cycle) => {
+                            let moved = self.debugger.run_to_cycle(cycle);
+                            self.moved(moved);
+                        }
+                        Err(_) => self.status = format!("Bad cycle '{input}'."),
+                    }
+                }
+                _ => self.cycle_input = None,
+            }
+            return true;
+        }
+
+        match key {
+            KeyCode::Char('q') | KeyCode::Esc => return false,
+            KeyCode::Char('s') | KeyCode::Right => {
+                let moved = self.debugger.step();
+                self.moved(moved);
+            }
+            KeyCode::Char('n') | KeyCode::Down => {
+                let moved = self.debugger.step_over();
+                self.moved(moved);
+            }
+            KeyCode::Char('o') | KeyCode::Up => {
+                let moved = self.debugger.step_out();
+                self.moved(moved);
+            }
+            KeyCode::Char('b') | KeyCode::Left => {
+                let moved = self.debugger.step_back();
+                self.moved(moved);
+            }
+            KeyCode::Char('r') => {
+                self.debugger.run_to_end();
+                self.moved(false);
+            }
+            KeyCode::Char('g') => self.cycle_input = Some(String::new()),
+            _ => {}
+        }
+        true
+    }
+
+    fn moved(&mut self, moved: bool) {
+        self.status = match self.debugger.error() {
+            Some(err) if self.debugger.at_end() => format!("Replay failed: {err}"),
+            _ if !moved && self.debugger.at_end() => "At the end of the trace.".to_string(),
+            _ if !moved && self.debugger.cursor() == 0 => "At the start of the trace.".to_string(),
+            _ => String::new(),
+        };
+    }
+
+    /// Draw the source, stack, memory and call stack panes for the current step.
+    pub fn draw(&self, frame: &mut Frame) {
+        let [main_area, status_area] =
+            Layout::vertical([Constraint::Min(0), Constraint::Length(1)]).areas(frame.area());
+        let [source_area, side_area] =
+            Layout::horizontal([Constraint::Percentage(60), Constraint::Percentage(40)])
+                .areas(main_area);
+        let [stack_area, memory_area, call_stack_area, notes_area] = Layout::vertical([
+            Constraint::Length(18),
+            Constraint::Min(4),
+            Constraint::Min(4),
+            Constraint::Min(4),
+        ])
+        .areas(side_area);
+
+        self.draw_source(frame, source_area);
+        self.draw_stack(frame, stack_area);
+        self.draw_memory(frame, memory_area);
+        self.draw_call_stack(frame, call_stack_area);
+        self.draw_notes(frame, notes_area);
+
+        let status = match &self.cycle_input {
+            Some(input) => format!("Run to cycle: {input}"),
+            None if !self.status.is_empty() => self.status.clone(),
+            None => HELP.to_string(),
+        };
+        frame.render_widget(Paragraph::new(status), status_area);
+    }
+
+    fn draw_source(&self, frame: &mut Frame, area: Rect) {
+        let Some(step) = self.debugger.current() else {
+            frame.render_widget(
+                Paragraph::new("No ops replayed.").block(pane("Source")),
+                area,
+            );
+            return;
+        };
+
+        let blocks = self.debugger.blocks();
+        let Some(proc_key) = self.debugger.proc_of(step.pos.block) else {
+            return;
+        };
+
+        let mut current_line = 0;
+        let lines = debugger::proc_listing(blocks, proc_key)
+            .into_iter()
+            .enumerate()
+            .map(|(line_idx, (pos, text))| {
+                if pos == Some(step.pos) {
+                    current_line = line_idx;
+                    Line::styled(
+                        format!("> {text}"),
+                        Style::new().add_modifier(Modifier::REVERSED),
+                    )
+                } else {
+                    Line::raw(format!("  {text}"))
+                }
+            })
+            .collect::<Vec<_>>();
+
+        // Keep the current op in the middle of the pane.
+        let scroll = current_line.saturating_sub(usize::from(area.height / 2));
+        let title = format!(
+            "{} [cycle {}, step {}]",
+            step.func,
+            step.cycle,
+            self.debugger.cursor()
+        );
+        frame.render_widget(
+            Paragraph::new(lines)
+                .block(pane(&title))
+                .scroll((u16::try_from(scroll).unwrap_or(u16::MAX), 0)),
+            area,
+        );
+    }
+
+    fn draw_stack(&self, frame: &mut Frame, area: Rect) {
+        let lines = self
+            .debugger
+            .current()
+            .map(|step| {
+                step.stack
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, val)| Line::raw(format!("{idx:>2}: {}", render::format_felt(*val))))
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        frame.render_widget(Paragraph::new(lines).block(pane("Operand Stack")), area);
+    }
+
+    fn draw_memory(&self, frame: &mut Frame, area: Rect) {
+        let lines = self
+            .debugger
+            .memory()
+            .words()
+            .map(|(base_addr, words)| {
+                Line::raw(format!("{base_addr:0>8x}: {}", render::format_word(&words)))
+            })
+            .collect::<Vec<_>>();
+        frame.render_widget(Paragraph::new(lines).block(pane("Memory")), area);
+    }
+
+    fn draw_call_stack(&self, frame: &mut Frame, area: Rect) {
+        let lines = self
+            .debugger
+            .current()
+            .map(|step| {
+                step.call_stack
+                    .iter()
+                    .rev()
+                    .map(|frame| Line::raw(frame.func.as_str()))
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        frame.render_widget(Paragraph::new(lines).block(pane("Call Stack")), area);
+    }
+
+    fn draw_notes(&self, frame: &mut Frame, area: Rect) {
+        let mut text = Text::default();
+        if let Some(step) = self.debugger.current() {
+            for note in &step.notes {
+                text.extend(Text::raw(note.as_str()));
+            }
+        }
+        frame.render_widget(Paragraph::new(text).block(pane("Notes")), area);
+    }
+}
+
+fn pane(title: &str) -> Block<'_> {
+    Block::bordered().title(title)
+}
+
+// vim:fdl=3
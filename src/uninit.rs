@@ -0,0 +1,72 @@
+use std::{collections::BTreeMap, io::Write};
+
+use crate::replay::ReplayEvent;
+
+/// Collects the loads of memory which hadn't been stored to, by the procedure which loaded it.
+/// The replay must be run with `check_uninit` set.
+#[derive(Debug, Default)]
+pub struct UninitReads {
+    // The loads of each felt address by each procedure.
+    reads: BTreeMap<String, BTreeMap<u64, Vec<UninitRead>>>,
+}
+
+/// A load of a felt which hadn't been stored to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UninitRead {
+    pub cycle: usize,
+    pub loaded: u64,
+}
+
+impl UninitReads {
+    pub fn record(&mut self, event: &ReplayEvent) {
+        if let ReplayEvent::UninitRead {
+            addr,
+            cycle,
+            func,
+            loaded,
+        } = event
+        {
+            self.reads
+                .entry(func.clone())
+                .or_default()
+                .entry(*addr)
+                .or_default()
+                .push(UninitRead {
+                    cycle: *cycle,
+                    loaded: *loaded,
+                });
+        }
+    }
+
+    /// The loads by each procedure, of each felt address, in order.
+    pub fn reads(&self) -> &BTreeMap<String, BTreeMap<u64, Vec<UninitRead>>> {
+        &self.reads
+    }
+
+    pub fn write_report(&self, mut out: impl Write) -> anyhow::Result<()> {
+        if self.reads.is_empty() {
+            writeln!(out, "No loads of unwritten memory.")?;
+            return Ok(());
+        }
+
+        for (func, addrs) in &self.reads {
+            writeln!(out, "{func}")?;
+            for (addr, reads) in addrs {
+                let first = &reads[0];
+                write!(
+                    out,
+                    "    addr {addr:x}: loaded {:x} at cycle {}",
+                    first.loaded, first.cycle
+                )?;
+                if reads.len() > 1 {
+                    write!(out, ", and {} more times", reads.len() - 1)?;
+                }
+                writeln!(out)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// vim:fdl=3
@@ -1,15 +1,47 @@
 fn check(log_path: &str, entry_point: &str) {
+    let expected_path = log_path.to_string() + ".expected";
+    check_with_args(log_path, &["-e", entry_point], &expected_path);
+}
+
+fn check_with_args(log_path: &str, args: &[&str], expected_path: &str) {
     let full_log_path = "tests/".to_string() + log_path;
     let output = test_bin::get_test_bin!("etp")
-        .args(["-e", entry_point, &full_log_path])
+        .args(args)
+        .arg(&full_log_path)
         .output()
         .expect("Failed to run `etp`");
     assert!(output.status.success());
 
-    let expected_path = log_path.to_string() + ".expected";
     expect_test::expect_file!(expected_path).assert_eq(&String::from_utf8_lossy(&output.stdout));
 }
 
+// Check the file written to the path following `args`, named like the expected file.  Returns
+// the output and the file.
+fn check_output_file(log_path: &str, args: &[&str], expected_path: &str) -> (String, String) {
+    let file_name = expected_path.trim_end_matches(".expected");
+    let out_path =
+        std::env::temp_dir().join(format!("etp-test-{}-{file_name}", std::process::id()));
+    let output = test_bin::get_test_bin!("etp")
+        .args(args)
+        .arg(&out_path)
+        .arg("tests/".to_string() + log_path)
+        .output()
+        .expect("Failed to run `etp`");
+    assert!(output.status.success());
+
+    let out_str = std::fs::read_to_string(&out_path).unwrap();
+    std::fs::remove_file(&out_path).unwrap();
+    expect_test::expect_file!(expected_path).assert_eq(&out_str);
+
+    (String::from_utf8_lossy(&output.stdout).to_string(), out_str)
+}
+
+fn replay(log_path: &str, options: etp::ReplayOptions) -> etp::Replay {
+    let log_str = std::fs::read_to_string("tests/".to_string() + log_path).unwrap();
+    let (blocks, trace) = etp::parse_trace(&log_str).unwrap();
+    etp::Replay::new(blocks, trace.into_iter().map(Ok), options).unwrap()
+}
+
 #[test]
 fn test_short() {
     check("short.log", "main");
@@ -19,3 +51,913 @@ fn test_short() {
 fn test_assert() {
     check("break_on_assert.log", "#run");
 }
+
+#[test]
+fn test_max_depth() {
+    check_with_args(
+        "break_on_assert.log",
+        &["--max-depth", "1", "-e", "#run"],
+        "break_on_assert.log.depth.expected",
+    );
+}
+
+#[test]
+fn test_failure_report() {
+    let log_str = std::fs::read_to_string("tests/break_on_assert.log").unwrap();
+    let (_, trace) = etp::parse_trace(&log_str).unwrap();
+
+    assert!(trace[..trace.len() - 1].iter().all(|t| t.failure.is_none()));
+
+    let report = trace.last().unwrap().failure.as_ref().unwrap();
+    assert_eq!(
+        report.stack_trace,
+        [
+            "miden:base/note-script@1.0.0::init",
+            "miden:base/note-script@1.0.0::run"
+        ]
+    );
+    assert_eq!(
+        report.last_instructions,
+        ["cswap", "drop", "pad", "eq", "pad"]
+    );
+    assert_eq!(
+        report.last_instructions_any_frame,
+        ["eq", "end", "split", "span", "pad"]
+    );
+    assert_eq!(report.frame_pointer, Some(1 << 30));
+    assert_eq!(report.operand_stack, [0; 19]);
+    assert_eq!((report.step, report.cycle), (3760, 3759));
+    assert_eq!(report.error_code(), Some("0"));
+
+    assert!(
+        report.matches_call_stack(&[
+            "miden:base/note-script@1.0.0::cm_types_script::miden:base/note-script@1.0.0#run"
+                .to_string()
+        ])
+    );
+    assert!(!report.matches_call_stack(&["foo::run".to_string(), "foo::bar".to_string()]));
+
+    // Stacks of different depths don't match, even when their innermost procedures do.
+    assert!(!report.matches_call_stack(&[]));
+    assert!(!report.matches_call_stack(&["foo::main".to_string(), "foo::run".to_string()]));
+    assert!(!report.matches_call_stack(&[
+        "foo::main".to_string(),
+        "foo::init".to_string(),
+        "foo::run".to_string()
+    ]));
+    let no_init = etp::failure::FailureReport {
+        stack_trace: vec!["foo::main".to_string(), "foo::run".to_string()],
+        ..(**report).clone()
+    };
+    assert!(no_init.matches_call_stack(&["foo::main".to_string(), "foo::run".to_string()]));
+    assert!(!no_init.matches_call_stack(&["foo::run".to_string()]));
+}
+
+#[test]
+fn test_keyword_prefixes() {
+    // Opcodes may begin with a keyword.
+    let log_str = "\
+    # mod root_ns:root@1.0.0::test
+    export.main
+        whilex
+        repeat_count
+        end_
+        if.true
+            iffy
+        else
+            elsewhere
+        end
+    end
+
+    stderr ───
+    [TRACE executor] in $exec::$main (no source location available)
+    [TRACE executor]   executed `Noop` of `nop` (cycle 1/1)
+    [TRACE executor]   stack state: [
+            0,
+        ]
+";
+    let (blocks, _) = etp::parse_trace(log_str).unwrap();
+    let ops = blocks
+        .values()
+        .flat_map(|block| block.ops())
+        .map(|op| op.to_string())
+        .collect::<Vec<_>>();
+    for opcode in ["whilex", "repeat_count", "end_", "iffy", "elsewhere"] {
+        assert!(ops.iter().any(|op| op == opcode), "{opcode} not in {ops:?}");
+    }
+}
+
+#[test]
+fn test_while_loop() {
+    check("while_loop.log", "main");
+
+    // The condition is left by the skipped call ending the body, and an empty body isn't looped.
+    check("while_skipped.log", "main");
+}
+
+#[test]
+fn test_repeat() {
+    check("repeat.log", "main");
+}
+
+#[test]
+fn test_conditionals() {
+    check("conditionals.log", "main");
+}
+
+#[test]
+fn test_diverged() {
+    check("diverged.log", "main");
+
+    // Strict mode gives up at the mismatch.
+    let output = test_bin::get_test_bin!("etp")
+        .args(["-e", "main", "--strict", "tests/diverged.log"])
+        .output()
+        .expect("Failed to run `etp`");
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("src op Op { opcode: \"add\""));
+}
+
+#[test]
+fn test_resync_block_end() {
+    // A mismatch is resynced with what's left of its block, but not as the last op of the block.
+    let output = test_bin::get_test_bin!("etp")
+        .args(["-e", "main", "tests/resync_block_end.log"])
+        .output()
+        .expect("Failed to run `etp`");
+    assert!(!output.status.success());
+    expect_test::expect_file!("resync_block_end.log.expected")
+        .assert_eq(&String::from_utf8_lossy(&output.stdout));
+}
+
+#[test]
+fn test_memory() {
+    check("memory.log", "main");
+
+    let options = etp::ReplayOptions {
+        entry_func: Some("main".to_string()),
+        ..Default::default()
+    };
+    let mut replay = replay("memory.log", options);
+    for event in replay.by_ref() {
+        assert!(!matches!(
+            event.unwrap(),
+            etp::ReplayEvent::MemoryMismatch { .. }
+        ));
+    }
+
+    // The word stores put the top of the stack at the highest address, and locals are addressed
+    // from the frame pointer.
+    let memory = replay.memory();
+    assert_eq!(memory.word_at(64), [Some(5), Some(6), Some(7), Some(8)]);
+    assert_eq!(memory.get(1 << 30), Some(5));
+    assert_eq!(memory.get(68), Some(0));
+    assert_eq!(etp::memory::native_ptr_byte_addr(0x44008, 2), 0x110022);
+}
+
+#[test]
+fn test_memory_first_cycle() {
+    // Without an entry point a replay of `run` starts at the first trace event, which has no
+    // prior one.
+    let log_str = std::fs::read_to_string("tests/memory.log")
+        .unwrap()
+        .replace("export.main", "export.run")
+        .replace("test::main", "test::run");
+    let (blocks, trace) = etp::parse_trace(&log_str).unwrap();
+    let replay = etp::Replay::new(
+        blocks,
+        trace.into_iter().skip(1).map(Ok),
+        etp::ReplayOptions::default(),
+    )
+    .unwrap();
+    assert!(replay.into_iter().all(|event| event.is_ok()));
+}
+
+#[test]
+fn test_memory_word_order() {
+    // A real `mem_storew`, read back a felt at a time by `load_dw`'s `mem_load`s.
+    check("mem_word.log", "main");
+
+    let options = etp::ReplayOptions {
+        entry_func: Some("main".to_string()),
+        ..Default::default()
+    };
+    let mut replay = replay("mem_word.log", options);
+    for event in replay.by_ref() {
+        assert!(!matches!(
+            event.unwrap(),
+            etp::ReplayEvent::MemoryMismatch { .. }
+        ));
+    }
+
+    // The word was [0, 0, 2137314310, 1181149911] on the stack, top first.
+    let memory = replay.memory();
+    assert_eq!(memory.get(278528), Some(1181149911));
+    assert_eq!(memory.get(278529), Some(2137314310));
+    assert_eq!(memory.get(278530), Some(0));
+    assert_eq!(memory.get(278531), Some(0));
+}
+
+#[test]
+fn test_memory_watch() {
+    check_with_args(
+        "memory.log",
+        &["--watch", "0x41..67", "-e", "main"],
+        "memory.log.watch.expected",
+    );
+    check_with_args(
+        "memory.log",
+        &["--uninit", "-e", "main"],
+        "memory.log.uninit.expected",
+    );
+
+    // Only the second half of the word streamed from 64 was never stored to.
+    let options = etp::ReplayOptions {
+        entry_func: Some("main".to_string()),
+        check_uninit: true,
+        ..Default::default()
+    };
+    let replay = replay("memory.log", options);
+    let mut uninit_reads = etp::uninit::UninitReads::default();
+    for event in replay {
+        uninit_reads.record(&event.unwrap());
+    }
+    let reads = &uninit_reads.reads()["root_ns:root@1.0.0::test::main"];
+    assert_eq!(reads.keys().copied().collect::<Vec<_>>(), [68, 69, 70, 71]);
+}
+
+#[test]
+fn test_mangled() {
+    check("mangled.log", "main");
+
+    // Raw symbols are still demangled to match the trace against the source, but shown mangled.
+    let output = test_bin::get_test_bin!("etp")
+        .args([
+            "-e",
+            "main",
+            "--raw-symbols",
+            "--format",
+            "json",
+            "tests/mangled.log",
+        ])
+        .output()
+        .expect("Failed to run `etp`");
+    assert!(output.status.success());
+
+    let output = String::from_utf8_lossy(&output.stdout);
+    assert!(output.contains(r#""func":"root_ns:root@1.0.0::_ZN4test4main17h0123456789abcdefE""#));
+    assert!(output.contains(r#""event":"entry_returned""#));
+}
+
+#[test]
+fn test_demangle() {
+    use etp::demangle::demangle;
+
+    for (mangled, expected) in [
+        ("$exec::$main", "$exec::$main"),
+        (
+            "miden:base/note-script@1.0.0::_ZN11wit_bindgen2rt14run_ctors_once17he09680b732c40ae6E",
+            "miden:base/note-script@1.0.0::wit_bindgen::rt::run_ctors_once",
+        ),
+        (
+            "_ZN71_$LT$Test$u20$$u2b$$u20$$u27$static$u20$as$u20$foo..Bar$LT$Test$GT$$GT$3bar17h930b740aa94f1d3aE",
+            "<Test + 'static as foo::Bar<Test>>::bar",
+        ),
+        (
+            "_ZN4core3ptr85drop_in_place$LT$std..rt..lang_start$LT$$LP$$RP$$GT$..$u7b$$u7b$closure$u7d$$u7d$$GT$17h0b0a4fb2a9c5f6e8E",
+            "core::ptr::drop_in_place<std::rt::lang_start<()>::{{closure}}>",
+        ),
+        (
+            "cm_types::_RNvNtCs1234_7mycrate3foo3bar",
+            "cm_types::mycrate::foo::bar",
+        ),
+        (
+            "_RNCNvCsgStHSCytQ6I_7mycrate4main0B3_",
+            "mycrate::main::{closure#0}",
+        ),
+        (
+            "_RINvCs1234_7mycrate3fooNtB2_3BarE",
+            "mycrate::foo::<mycrate::Bar>",
+        ),
+    ] {
+        assert_eq!(demangle(mangled).unwrap(), expected);
+    }
+
+    assert!(demangle("foo::_ZNbroken").is_err());
+}
+
+#[test]
+fn test_json_format() {
+    check_with_args(
+        "short.log",
+        &["-e", "main", "-f", "json"],
+        "short.log.json.expected",
+    );
+
+    // Each memory access directly follows the op or intrinsic which made it.
+    for (log_path, entry_func) in [
+        ("tests/memory.log", "main"),
+        ("tests/intrinsics.log", "main"),
+        ("tests/break_on_assert.log", "#run"),
+    ] {
+        let output = test_bin::get_test_bin!("etp")
+            .args(["-e", entry_func, "--check", "-f", "json", log_path])
+            .output()
+            .expect("Failed to run `etp`");
+        assert!(output.status.success());
+
+        let events = String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["event"].clone())
+            .collect::<Vec<_>>();
+        assert!(events.contains(&"memory".into()));
+        for pair in events.windows(2) {
+            if pair[1] == "memory" {
+                assert!(["op", "skipped_cycles", "simulated"].contains(&pair[0].as_str().unwrap()));
+            }
+        }
+    }
+}
+
+#[test]
+fn test_profile() {
+    check_with_args(
+        "short.log",
+        &["-e", "main", "--profile"],
+        "short.log.profile.expected",
+    );
+
+    // The replay is still printed alongside the collapsed stacks.
+    let (replay_str, _) = check_output_file(
+        "short.log",
+        &["-e", "main", "--folded"],
+        "short.log.folded.expected",
+    );
+    expect_test::expect_file!("short.log.expected").assert_eq(&replay_str);
+}
+
+#[test]
+fn test_callgraph() {
+    check_output_file(
+        "break_on_assert.log",
+        &["-e", "#run", "--callgraph"],
+        "break_on_assert.log.callgraph.dot.expected",
+    );
+    check_output_file(
+        "short.log",
+        &["-e", "main", "--callgraph"],
+        "short.log.callgraph.json.expected",
+    );
+}
+
+#[test]
+fn test_chrome_trace() {
+    let (_, trace_str) = check_output_file(
+        "break_on_assert.log",
+        &["-e", "#run", "--chrome-trace"],
+        "break_on_assert.log.trace.json.expected",
+    );
+
+    // The last cycle replayed, which the failure follows.
+    let output = test_bin::get_test_bin!("etp")
+        .args(["-e", "#run", "-f", "json", "tests/break_on_assert.log"])
+        .output()
+        .expect("Failed to run `etp`");
+    let last_cycle = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .filter(|event| ["op", "skipped_cycles"].contains(&event["event"].as_str().unwrap()))
+        .filter_map(|event| event["cycle"].as_u64())
+        .max()
+        .unwrap();
+
+    // Every procedure begun is ended, innermost first, even though execution failed.  Events are
+    // timed by trace cycle, never going backwards or past the end of the replay.
+    let trace: serde_json::Value = serde_json::from_str(&trace_str).unwrap();
+    let mut open = Vec::new();
+    let mut prev_ts = 0;
+    for event in trace["traceEvents"].as_array().unwrap() {
+        let ts = event["ts"].as_u64().unwrap();
+        assert!(prev_ts <= ts && ts <= last_cycle);
+        prev_ts = ts;
+
+        match event["ph"].as_str().unwrap() {
+            "B" => open.push(event["name"].clone()),
+            "E" => assert_eq!(open.pop().as_ref(), Some(&event["name"])),
+            _ if event["name"] == "FAILED: assert" => assert_eq!(ts, last_cycle),
+            _ => {}
+        }
+    }
+    assert!(open.is_empty());
+}
+
+#[test]
+fn test_coverage() {
+    check_with_args(
+        "conditionals.log",
+        &["-e", "main", "--coverage"],
+        "conditionals.log.coverage.expected",
+    );
+    check_with_args(
+        "while_loop.log",
+        &["-e", "main", "--coverage"],
+        "while_loop.log.coverage.expected",
+    );
+}
+
+#[test]
+fn test_compressed() {
+    check_with_args("short.log.gz", &["-e", "main"], "short.log.expected");
+    check_with_args("short.log.zst", &["-e", "main"], "short.log.expected");
+}
+
+#[test]
+fn test_stdin() {
+    // The log is read from stdin given `-`, or no path at all.
+    for args in [&["-e", "main", "-"][..], &["-e", "main"]] {
+        let log_file = std::fs::File::open("tests/short.log.gz").unwrap();
+        let output = test_bin::get_test_bin!("etp")
+            .args(args)
+            .stdin(log_file)
+            .output()
+            .expect("Failed to run `etp`");
+        assert!(output.status.success());
+
+        expect_test::expect_file!("short.log.expected")
+            .assert_eq(&String::from_utf8_lossy(&output.stdout));
+    }
+}
+
+#[test]
+fn test_lib_replay() {
+    let options = etp::ReplayOptions {
+        entry_func: Some("main".to_string()),
+        ..Default::default()
+    };
+    let replay = replay("short.log", options);
+    let mut renderer = etp::TextRenderer::new(Vec::new());
+    for event in replay {
+        etp::Renderer::render(&mut renderer, &event.unwrap()).unwrap();
+    }
+
+    let out = renderer.into_inner();
+    expect_test::expect_file!("short.log.expected").assert_eq(&String::from_utf8_lossy(&out));
+}
+
+#[test]
+fn test_replay_events() {
+    use etp::replay::Branch;
+
+    let options = etp::ReplayOptions {
+        entry_func: Some("main".to_string()),
+        ..Default::default()
+    };
+    let branches = replay("conditionals.log", options)
+        .filter_map(|event| match event.unwrap() {
+            etp::ReplayEvent::BranchTaken { branch, taken, .. } => Some((branch, taken)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    assert_eq!(
+        branches,
+        [
+            (
+                Branch::If {
+                    on_true: true,
+                    has_else: false
+                },
+                true
+            ),
+            (
+                Branch::If {
+                    on_true: true,
+                    has_else: false
+                },
+                false
+            ),
+            (
+                Branch::If {
+                    on_true: false,
+                    has_else: true
+                },
+                true
+            ),
+            (
+                Branch::If {
+                    on_true: false,
+                    has_else: true
+                },
+                false
+            ),
+        ]
+    );
+}
+
+#[test]
+fn test_streamed_trace() {
+    use std::io::Read;
+
+    // A reader which fails, so we can tell how far the log has been read.
+    struct Truncated;
+
+    impl Read for Truncated {
+        fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::other("read too far"))
+        }
+    }
+
+    let log_str = std::fs::read_to_string("tests/short.log").unwrap();
+    let log_reader = std::io::BufReader::new(log_str.as_bytes().chain(Truncated));
+
+    // The source and early trace events are available without reading the whole log.
+    let (blocks, mut trace) = etp::parse_log(log_reader).unwrap();
+    assert!(!blocks.is_empty());
+    for _ in 0..10 {
+        assert!(trace.next().unwrap().is_ok());
+    }
+
+    assert!(trace.any(|trace_item| trace_item.is_err()));
+}
+
+#[test]
+fn test_trace_marker_padding() {
+    let log_str = std::fs::read_to_string("tests/short.log").unwrap();
+    let (_, trace) = etp::parse_trace(&log_str).unwrap();
+
+    // However the executor pads the start of an event, the reader still finds each one.
+    let padded = log_str
+        .split("[TRACE executor] in ")
+        .enumerate()
+        .map(|(idx, part)| match idx {
+            0 => part.to_string(),
+            _ if idx % 2 == 0 => format!("[TRACE executor]   in {part}"),
+            _ => format!("[TRACE executor]\tin  {part}"),
+        })
+        .collect::<String>();
+    let (_, padded_trace) = etp::parse_log(padded.as_bytes()).unwrap();
+    let padded_trace = padded_trace.collect::<anyhow::Result<Vec<_>>>().unwrap();
+    assert_eq!(padded_trace.len(), trace.len());
+    assert!(
+        padded_trace
+            .iter()
+            .zip(&trace)
+            .all(|(padded, trace)| padded.func == trace.func && padded.stack == trace.stack)
+    );
+}
+
+fn debugger(log_path: &str, entry_func: &str) -> etp::debugger::Debugger {
+    let options = etp::ReplayOptions {
+        entry_func: Some(entry_func.to_string()),
+        ..Default::default()
+    };
+    etp::debugger::Debugger::new(replay(log_path, options))
+}
+
+#[test]
+fn test_debugger() {
+    let mut debugger = debugger("break_on_assert.log", "#run");
+    let depth = |debugger: &etp::debugger::Debugger| debugger.current().unwrap().call_stack.len();
+
+    assert_eq!(debugger.current().unwrap().op.to_string(), "drop");
+    assert!(!debugger.step_back());
+    assert_eq!(depth(&debugger), 1);
+
+    // The skipped load_sw reads memory, which is forgotten again stepping back before it.
+    while debugger.memory().is_empty() {
+        assert!(debugger.step());
+    }
+    assert_eq!(debugger.cursor(), 8);
+    assert_eq!(
+        debugger.current().unwrap().op.to_string(),
+        "exec.::intrinsics::mem::load_sw"
+    );
+    assert_eq!(
+        debugger.memory().word_at(0x44008),
+        [Some(0x100000), None, None, None]
+    );
+    assert!(debugger.step_back());
+    assert!(debugger.memory().is_empty());
+
+    // Stepping into run_ctors_once then out of it arrives where stepping over it does.
+    assert!(debugger.run_to_cycle(0));
+    while depth(&debugger) == 1 {
+        assert!(debugger.step());
+    }
+    let callee_step = debugger.cursor();
+    assert!(debugger.step_out());
+    assert_eq!(depth(&debugger), 1);
+    let after_call = debugger.cursor();
+
+    assert!(debugger.run_to_cycle(0));
+    assert_eq!(debugger.cursor(), 0);
+    while debugger.cursor() + 1 < callee_step {
+        assert!(debugger.step());
+    }
+    assert!(debugger.step_over());
+    assert_eq!(debugger.cursor(), after_call);
+
+    // Running to a cycle goes backwards as well as forwards.
+    let cycle = debugger.current().unwrap().cycle;
+    assert!(debugger.run_to_cycle(cycle + 100));
+    assert!(debugger.current().unwrap().cycle >= cycle + 100);
+    assert!(debugger.run_to_cycle(cycle));
+    assert_eq!(debugger.cursor(), after_call);
+
+    debugger.run_to_end();
+    assert!(debugger.at_end());
+    assert!(!debugger.step());
+
+    // At the end the memory is the replay's model, not only the words reported as accessed.
+    let options = etp::ReplayOptions {
+        entry_func: Some("#run".to_string()),
+        ..Default::default()
+    };
+    let mut replay = replay("break_on_assert.log", options);
+    for event in replay.by_ref() {
+        event.unwrap();
+    }
+    assert!(!replay.memory().is_empty());
+    assert!(debugger.memory().words().eq(replay.memory().words()));
+    assert!(
+        debugger
+            .current()
+            .unwrap()
+            .notes
+            .iter()
+            .any(|note| note.contains("FAILED HERE"))
+    );
+}
+
+#[test]
+fn test_tui_draw() {
+    use ratatui::crossterm::event::KeyCode;
+
+    let mut tui = etp::tui::Tui::new(debugger("conditionals.log", "main"));
+    for key in ['s', 'n', 'b'] {
+        assert!(tui.handle_key(KeyCode::Char(key)));
+    }
+
+    let mut terminal = ratatui::Terminal::new(ratatui::backend::TestBackend::new(100, 30)).unwrap();
+    terminal.draw(|frame| tui.draw(frame)).unwrap();
+    let screen = terminal
+        .backend()
+        .buffer()
+        .content()
+        .chunks(100)
+        .map(|row| row.iter().map(|cell| cell.symbol()).collect::<String>())
+        .collect::<Vec<_>>();
+
+    assert!(
+        screen
+            .iter()
+            .any(|row| row.starts_with("│>") && row.contains(" push.10 "))
+    );
+    assert!(screen.iter().any(|row| row.contains("Operand Stack")));
+    assert!(screen.iter().any(|row| row.contains(" 0: 10 ")));
+    assert!(!tui.handle_key(KeyCode::Char('q')));
+}
+
+#[test]
+fn test_dap() {
+    use serde_json::{Value, json};
+
+    let requests = [
+        json!({ "command": "initialize", "arguments": { "adapterID": "etp" } }),
+        json!({
+            "command": "launch",
+            "arguments": { "program": "tests/break_on_assert.log", "entryFunc": "#run" },
+        }),
+        json!({
+            "command": "setFunctionBreakpoints",
+            "arguments": {
+                "breakpoints": [{ "name": "cm_types_script::wit_bindgen::rt::run_ctors_once" }],
+            },
+        }),
+        json!({ "command": "configurationDone" }),
+        json!({ "command": "continue", "arguments": { "threadId": 1 } }),
+        json!({ "command": "stackTrace", "arguments": { "threadId": 1 } }),
+        json!({ "command": "source", "arguments": { "sourceReference": 1 } }),
+        json!({ "command": "variables", "arguments": { "variablesReference": 1 } }),
+        json!({ "command": "variables", "arguments": { "variablesReference": 2 } }),
+        json!({ "command": "stepBack", "arguments": { "threadId": 1 } }),
+        json!({ "command": "stackTrace", "arguments": { "threadId": 1 } }),
+        json!({ "command": "continue", "arguments": { "threadId": 1 } }),
+        json!({ "command": "continue", "arguments": { "threadId": 1 } }),
+        json!({ "command": "disconnect" }),
+    ];
+
+    let mut input = Vec::new();
+    for (seq, mut request) in requests.into_iter().enumerate() {
+        request["seq"] = (seq + 1).into();
+        request["type"] = "request".into();
+        let content = request.to_string();
+        input.extend(format!("Content-Length: {}\r\n\r\n{content}", content.len()).bytes());
+    }
+
+    let mut server = etp::dap::DapServer::new(input.as_slice(), Vec::new());
+    server.run().unwrap();
+    let output = String::from_utf8(server.into_inner()).unwrap();
+
+    let messages = output
+        .split("Content-Length: ")
+        .skip(1)
+        .map(|message| {
+            let (_, content) = message.split_once("\r\n\r\n").unwrap();
+            serde_json::from_str::<Value>(content).unwrap()
+        })
+        .collect::<Vec<_>>();
+    assert!(messages.iter().all(|message| message["success"] != false));
+
+    let response = |request_seq: u64| {
+        &messages
+            .iter()
+            .find(|message| message["request_seq"] == request_seq)
+            .unwrap()["body"]
+    };
+    let stops = messages
+        .iter()
+        .filter(|message| message["event"] == "stopped")
+        .map(|message| message["body"]["reason"].as_str().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        stops,
+        ["entry", "breakpoint", "step", "breakpoint", "exception"]
+    );
+
+    // Stopped at the start of run_ctors_once, called from #run.
+    let frames = response(6)["stackFrames"].as_array().unwrap();
+    assert_eq!(frames.len(), 2);
+    assert!(
+        frames[0]["name"]
+            .as_str()
+            .unwrap()
+            .ends_with("run_ctors_once")
+    );
+    assert!(frames[1]["name"].as_str().unwrap().ends_with("#run"));
+    assert_eq!(frames[0]["line"], 1);
+    assert!(frames[1]["line"].as_u64().unwrap() > 1);
+
+    let listing = response(7)["content"].as_str().unwrap();
+    assert!(listing.starts_with("push.1114148\n"));
+
+    let stack = response(8)["variables"].as_array().unwrap();
+    assert_eq!(stack[0]["value"], "110024h");
+    let memory = response(9)["variables"].as_array().unwrap();
+    assert_eq!(memory[0]["name"], "00044008");
+
+    assert_eq!(response(11)["stackFrames"].as_array().unwrap().len(), 1);
+}
+
+#[test]
+fn test_semantics() {
+    use etp::{masm::Op, semantics};
+
+    check_with_args(
+        "semantics.log",
+        &["--check", "-e", "main"],
+        "semantics.log.expected",
+    );
+
+    let eval = |asm: &str, stack: &[u64]| {
+        let (opcode, arg) = match asm.split_once('.') {
+            Some((opcode, arg)) => (opcode, Some(arg.to_string())),
+            None => (asm, None),
+        };
+        let op = Op::Op {
+            opcode: opcode.to_string(),
+            arg,
+        };
+        semantics::eval(&op, stack).map(|stack| stack[..4].to_vec())
+    };
+
+    let p = semantics::FIELD_MODULUS;
+    assert_eq!(eval("add", &[1, p - 1]), Some(vec![0, 0, 0, 0]));
+    assert_eq!(eval("sub.3", &[2]), Some(vec![p - 1, 0, 0, 0]));
+    assert_eq!(
+        eval("u32divmod.4", &[0x110020]),
+        Some(vec![0, 0x44008, 0, 0])
+    );
+    assert_eq!(
+        eval("u32wrapping_sub", &[16, 0]),
+        Some(vec![0xffff_fff0, 0, 0, 0])
+    );
+    assert_eq!(eval("movup.2", &[1, 2, 3, 4]), Some(vec![3, 1, 2, 4]));
+    assert_eq!(eval("movdn.2", &[1, 2, 3, 4]), Some(vec![2, 3, 1, 4]));
+    assert_eq!(eval("cdrop", &[1, 5, 6, 7]), Some(vec![5, 7, 0, 0]));
+    assert_eq!(eval("cswap", &[0, 5, 6, 7]), Some(vec![5, 6, 7, 0]));
+    assert_eq!(eval("u32shl.8", &[0xff]), Some(vec![0xff00, 0, 0, 0]));
+    assert_eq!(
+        eval("mul", &[semantics::inv(3).unwrap(), 3]),
+        Some(vec![1, 0, 0, 0])
+    );
+
+    // Ops which would fail, or whose results aren't on the stack, aren't evaluated.
+    assert_eq!(eval("u32assert", &[1 << 32]), None);
+    assert_eq!(eval("div", &[0, 1]), None);
+    assert_eq!(eval("mem_load", &[0]), None);
+}
+
+#[test]
+fn test_intrinsics() {
+    check_with_args(
+        "intrinsics.log",
+        &["--check", "-e", "main"],
+        "intrinsics.log.expected",
+    );
+}
+
+#[test]
+fn test_diff() {
+    check_with_args(
+        "diff_b.log",
+        &["diff", "-e", "main", "tests/diff_a.log"],
+        "diff_b.log.diff.expected",
+    );
+
+    assert!(
+        etp::diff::diff(
+            debugger("diff_a.log", "main"),
+            debugger("diff_a.log", "main")
+        )
+        .is_none()
+    );
+
+    let divergence = etp::diff::diff(
+        debugger("diff_a.log", "main"),
+        debugger("diff_b.log", "main"),
+    )
+    .unwrap();
+    assert_eq!(divergence.kind, etp::diff::DivergenceKind::Stack);
+    assert_eq!(divergence.step_idx, 3);
+}
+
+#[test]
+fn test_provenance() {
+    check_with_args(
+        "provenance.log",
+        &["--explain-top", "-e", "main"],
+        "provenance.log.expected",
+    );
+
+    let options = etp::ReplayOptions {
+        entry_func: Some("main".to_string()),
+        ..Default::default()
+    };
+    let replay = replay("provenance.log", options);
+
+    // Just after the call to `scale`, the 7 pushed by main sits beneath its result.
+    let mut provenance = etp::provenance::Provenance::default();
+    for event in replay {
+        let event = event.unwrap();
+        if matches!(event, etp::ReplayEvent::OpExecuted { cycle, .. } if cycle > 9) {
+            break;
+        }
+        provenance.record(&event);
+    }
+    let pushed = provenance.producer(provenance.producer_of(1).unwrap());
+    assert_eq!(
+        (pushed.op.to_string().as_str(), pushed.cycle),
+        ("push.7", 2)
+    );
+
+    let scaled = provenance.producer(provenance.producer_of(0).unwrap());
+    assert_eq!(scaled.func, "root_ns:root@1.0.0::test::scale");
+    assert_eq!(scaled.inputs.len(), 2);
+}
+
+#[test]
+fn test_filter() {
+    check_with_args(
+        "provenance.log",
+        &["--hide", "scale", "-e", "main"],
+        "provenance.log.hide.expected",
+    );
+    check_with_args(
+        "provenance.log",
+        &["--from-cycle", "7", "--to-cycle", "12", "-e", "main"],
+        "provenance.log.cycles.expected",
+    );
+
+    // Only the call to `scale` and its return are shown, though memory is still followed.
+    let options = etp::ReplayOptions {
+        entry_func: Some("main".to_string()),
+        ..Default::default()
+    };
+    let mut replay = replay("provenance.log", options);
+    let mut filter = etp::filter::Filter::new(etp::filter::FilterOptions {
+        only: vec!["scale".to_string()],
+        ..Default::default()
+    });
+    let mut shown = Vec::new();
+    for event in replay.by_ref() {
+        shown.extend(filter.filter(event.unwrap()));
+    }
+    assert!(matches!(shown[0], etp::ReplayEvent::Enter { .. }));
+    assert!(matches!(
+        shown.last(),
+        Some(etp::ReplayEvent::Return { .. })
+    ));
+    assert_eq!(shown.len(), 4);
+    assert_eq!(replay.memory().get(64), Some(5));
+}